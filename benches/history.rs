@@ -0,0 +1,132 @@
+//! Storage size and read latency of state history for a workspace with 1000
+//! versions, comparing full copies against snapshots with deltas.
+//!
+//! Run with `cargo bench --bench history`.
+use std::str::FromStr;
+
+use criterion::{
+    BenchmarkId,
+    Criterion,
+    criterion_group,
+    criterion_main,
+};
+use serde_json::json;
+use sqlx::{
+    SqlitePool,
+    sqlite::SqliteConnectOptions,
+};
+use tokio::runtime::Runtime;
+
+use terraform_http_backend_rs::{
+    codec::StateCodec,
+    database,
+    db::terraform::TerraformQuery,
+    history::HistoryMode,
+};
+
+
+const VERSIONS: i64 = 1000;
+const RESOURCES: usize = 200;
+const SNAPSHOT_INTERVAL: i64 = 20;
+const WORKSPACE: &str = "bench";
+
+
+/// A state resembling terraform output, where each apply changes a handful of
+/// resources
+fn state(serial: i64) -> String {
+    let resources: Vec<_> = (0..RESOURCES)
+        .map(|i| {
+            let generation = if i as i64 % 50 == serial % 50 { serial } else { 0 };
+
+            json!({
+                "mode": "managed",
+                "type": "aws_instance",
+                "name": format!("web_{}", i),
+                "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+                "instances": [{
+                    "schema_version": 1,
+                    "attributes": {
+                        "id": format!("i-{:017x}", i),
+                        "ami": "ami-0c55b159cbfafe1f0",
+                        "instance_type": "t3.micro",
+                        "tags": {"Name": format!("web-{}", i), "Generation": generation.to_string()},
+                        "private_ip": format!("10.0.{}.{}", i / 250, i % 250),
+                    },
+                }],
+            })
+        })
+        .collect();
+
+    json!({
+        "version": 4,
+        "terraform_version": "1.0.11",
+        "serial": serial,
+        "lineage": "b8c6a3c5-2a3f-6b1e-4b9b-8f2b3f0e0d1a",
+        "outputs": {},
+        "resources": resources,
+    }).to_string()
+}
+
+
+async fn populate(mode: HistoryMode) -> (SqlitePool, TerraformQuery) {
+    let opts = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap();
+    let pool = SqlitePool::connect_with(opts)
+        .await
+        .unwrap();
+
+    database::MIGRATE.run(&pool)
+        .await
+        .unwrap();
+
+    let query = TerraformQuery::new(pool.clone())
+        .with_codec(StateCodec::Zstd)
+        .with_history(mode, SNAPSHOT_INTERVAL);
+
+    for serial in 1..=VERSIONS {
+        query.create_or_replace(WORKSPACE, &state(serial))
+            .await
+            .unwrap();
+    }
+
+    (pool, query)
+}
+
+
+fn history(c: &mut Criterion) {
+    let runtime = Runtime::new()
+        .unwrap();
+    let mut group = c.benchmark_group("get_version");
+
+    for mode in [HistoryMode::Full, HistoryMode::Delta] {
+        let (pool, query) = runtime.block_on(populate(mode));
+        let (bytes,): (i64,) = runtime
+            .block_on(
+                sqlx::query_as("SELECT SUM(LENGTH(state)) FROM terraform_versions")
+                    .fetch_one(&pool)
+            )
+            .unwrap();
+
+        println!(
+            "{} history: {} versions stored in {} bytes ({} bytes uncompressed per version)",
+            mode,
+            VERSIONS,
+            bytes,
+            state(1).len(),
+        );
+
+        // a snapshot, the version with the longest chain of deltas since the
+        // snapshot before it, and one in between
+        for version in [VERSIONS - SNAPSHOT_INTERVAL + 1, VERSIONS, VERSIONS - SNAPSHOT_INTERVAL / 2] {
+            group.bench_with_input(BenchmarkId::new(mode.to_string(), version), &version, |b, &version| {
+                b.iter(|| runtime.block_on(query.get_version(WORKSPACE, version)).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+
+criterion_group!(benches, history);
+criterion_main!(benches);
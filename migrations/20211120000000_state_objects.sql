@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS state_objects (
+    terraform_id TEXT PRIMARY KEY,
+    version INTEGER NOT NULL,
+    object_key TEXT NOT NULL,
+    last_update_ts datetime NOT NULL DEFAULT current_timestamp
+);
+
+
+CREATE TRIGGER IF NOT EXISTS update_state_object_ts
+AFTER UPDATE ON state_objects
+BEGIN
+    UPDATE state_objects SET last_update_ts = current_timestamp WHERE terraform_id = NEW.terraform_id;
+END;
@@ -0,0 +1,2 @@
+ALTER TABLE terraform ADD COLUMN key_id TEXT;
+ALTER TABLE terraform ADD COLUMN data_key BLOB;
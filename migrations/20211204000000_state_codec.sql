@@ -0,0 +1,32 @@
+-- state bodies are stored as BLOBs alongside the codec used to encode them.
+-- encrypted rows were previously written as base64 text and are marked with
+-- the legacy `base64` codec until they are rewritten
+CREATE TABLE terraform_codec (
+    id TEXT PRIMARY KEY,
+    state BLOB NOT NULL,
+    codec TEXT NOT NULL DEFAULT 'identity',
+    key_id TEXT,
+    data_key BLOB,
+    last_update_ts datetime NOT NULL DEFAULT current_timestamp
+);
+
+INSERT INTO terraform_codec (id, state, codec, key_id, data_key, last_update_ts)
+SELECT
+    id,
+    CAST(state AS BLOB),
+    CASE WHEN key_id IS NULL THEN 'identity' ELSE 'base64' END,
+    key_id,
+    data_key,
+    last_update_ts
+FROM terraform;
+
+DROP TABLE terraform;
+
+ALTER TABLE terraform_codec RENAME TO terraform;
+
+
+CREATE TRIGGER IF NOT EXISTS update_resource_ts
+AFTER UPDATE ON terraform
+BEGIN
+    UPDATE terraform SET last_update_ts = current_timestamp WHERE id = NEW.id;
+END;
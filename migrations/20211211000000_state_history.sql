@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS terraform_versions (
+    terraform_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    state BLOB NOT NULL,
+    codec TEXT NOT NULL DEFAULT 'identity',
+    key_id TEXT,
+    data_key BLOB,
+    created_ts datetime NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (terraform_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS state_object_versions (
+    terraform_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    object_key TEXT NOT NULL,
+    created_ts datetime NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (terraform_id, version)
+);
+ALTER TABLE terraform ADD COLUMN version INTEGER;
@@ -0,0 +1,14 @@
+-- webhook deliveries waiting to be sent. rows are removed once delivered and
+-- kept with a NULL next_attempt_ts once their attempts are exhausted
+CREATE TABLE IF NOT EXISTS webhook_outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    hook TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_ts datetime,
+    last_error TEXT,
+    created_ts datetime NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX IF NOT EXISTS webhook_outbox_next_attempt ON webhook_outbox (next_attempt_ts);
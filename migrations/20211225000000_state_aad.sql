@@ -0,0 +1,4 @@
+-- bodies encrypted before associated data was length prefixed keep format 1
+-- until they are rewritten by rekey or recompress
+ALTER TABLE terraform ADD COLUMN aad_format INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE terraform_versions ADD COLUMN aad_format INTEGER NOT NULL DEFAULT 1;
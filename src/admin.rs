@@ -0,0 +1,733 @@
+use std::{
+    fs,
+    io::{
+        self,
+        Write,
+    },
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{
+    Context,
+    anyhow,
+    bail,
+};
+use rand_core::{
+    OsRng,
+    RngCore,
+};
+use serde_json::Value;
+use sqlx::SqlitePool;
+
+use crate::{
+    admission::Admission,
+    archive::{
+        self,
+        ImportOutcome,
+    },
+    auth::{
+        self,
+        sha256_hex,
+    },
+    backup,
+    cli::Command,
+    config::Configuration,
+    crypto::Keyring,
+    database,
+    db::terraform::TerraformQuery,
+    events::{
+        Event,
+        EventKind,
+    },
+    inventory::{
+        DuplicatePolicy,
+        Inventory,
+    },
+    local_backend::{
+        self,
+        Action,
+    },
+    models::state::State,
+    rules::{
+        self,
+        Rule,
+        RulesMode,
+    },
+    storage::{
+        self,
+        MEMORY_URI,
+        SharedStorage,
+        StorageBackend,
+    },
+    webhooks::{
+        self,
+        Webhook,
+    },
+};
+
+
+pub async fn run(command: Command, config: &Configuration) -> Result<(), anyhow::Error> {
+    match command {
+        Command::Migrate => migrate(config).await,
+        Command::List => list(&open_storage(config).await?).await,
+        Command::Show { id, version } => show(&open_storage(config).await?, &id, version).await,
+        Command::Unlock { id, force } => unlock(&open_storage(config).await?, &Webhook::from_config(config)?, &id, force).await,
+        Command::Export { id, output } => export(&open_storage(config).await?, &id, output.as_deref()).await,
+        Command::Import { id, file, force } => import(&Importer::new(config).await?, &id, &file, force).await,
+        Command::Backup { path } => backup(config, path.as_deref()).await,
+        Command::Restore { backup } => restore(config, &backup).await,
+        Command::ExportArchive { path } => export_archive(&open_storage(config).await?, &path).await,
+        Command::ImportArchive { path } => import_archive(&Importer::new(config).await?, &path).await,
+        Command::ImportLocal { dir, id_template, apply } => import_local(&Importer::new(config).await?, &dir, &id_template, apply).await,
+        Command::Verify => verify(&open_storage(config).await?).await,
+        Command::CheckRules { workspace, state, previous, rules } => check_rules(config, &workspace, &state, previous.as_deref(), rules.as_deref()),
+        Command::CreateToken { name } => create_token(&name),
+        Command::HashPassword { name } => hash_password(&name),
+        Command::Rekey => rekey(config).await,
+        Command::Recompress => recompress(config).await,
+    }
+}
+
+
+/// Opens the configured storage, refusing to touch a database that the
+/// server has not yet migrated
+async fn open_storage(config: &Configuration) -> Result<SharedStorage, anyhow::Error> {
+    let pool = open_database(config)
+        .await?;
+
+    Ok(storage::from_config(config, pool).await?)
+}
+
+
+/// Opens the configured database, failing if any migration is pending
+async fn open_database(config: &Configuration) -> Result<SqlitePool, anyhow::Error> {
+    if config.database_uri == MEMORY_URI {
+        bail!("{} storage only exists inside a running server", MEMORY_URI);
+    }
+
+    let pool = database::get_db_pool(config)
+        .await
+        .context("Failed to open database")?;
+    let pending = database::pending_migrations(&pool)
+        .await?;
+
+    if !pending.is_empty() {
+        bail!("{} database migrations are not applied; run `migrate` first", pending.len());
+    }
+
+    Ok(pool)
+}
+
+
+/// Writes states the way `POST /terraform/<id>` would: checked against the
+/// rules and the other workspaces, with `state.written` webhooks queued for
+/// the server to deliver
+struct Importer {
+    storage: SharedStorage,
+    rules: Vec<Rule>,
+    rules_mode: RulesMode,
+    duplicates: DuplicatePolicy,
+    inventory: Inventory,
+    hooks: Vec<Webhook>,
+}
+
+
+impl Importer {
+    async fn new(config: &Configuration) -> Result<Self, anyhow::Error> {
+        let storage = open_storage(config)
+            .await?;
+        let inventory = Inventory::new();
+
+        inventory.rebuild(&storage)
+            .await?;
+
+        Ok(Self {
+            storage,
+            rules: Rule::from_config(config)?,
+            rules_mode: config.rules_mode,
+            duplicates: config.duplicate_resources,
+            inventory,
+            hooks: Webhook::from_config(config)?,
+        })
+    }
+
+    fn admission(&self) -> Admission<'_> {
+        Admission {
+            rules: &self.rules,
+            rules_mode: self.rules_mode,
+            duplicates: self.duplicates,
+            inventory: &self.inventory,
+        }
+    }
+
+    /// Queues `state.written` webhooks for states this process wrote
+    async fn written(&self, ids: &[&str]) -> Result<(), anyhow::Error> {
+        if let Some(pool) = self.storage.pool() {
+            for id in ids {
+                let event = Event::new(EventKind::StateWritten, id)
+                    .with_actor(&whoami());
+
+                webhooks::enqueue(pool, &self.hooks, &event)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+async fn migrate(config: &Configuration) -> Result<(), anyhow::Error> {
+    let pool = database::get_db_pool(config)
+        .await
+        .context("Failed to open database")?;
+    let pending = database::pending_migrations(&pool)
+        .await?;
+
+    database::MIGRATE.run(&pool)
+        .await?;
+
+    println!("Applied {} migrations", pending.len());
+
+    Ok(())
+}
+
+
+async fn list(storage: &SharedStorage) -> Result<(), anyhow::Error> {
+    let states = storage
+        .list()
+        .await?;
+    let locks = storage
+        .list_locks()
+        .await?;
+
+    for state in states {
+        let lock = locks
+            .iter()
+            .find(|lock| lock.terraform_id == state.id)
+            .map(|lock| format!("locked by {} since {}", lock.id, lock.last_update_ts))
+            .unwrap_or_default();
+
+        println!("{}\t{}\t{}", state.id, state.last_update_ts, lock);
+    }
+
+    Ok(())
+}
+
+
+async fn show(storage: &SharedStorage, id: &str, version: Option<i64>) -> Result<(), anyhow::Error> {
+    let row = match version {
+        Some(version) => storage.get_version(id, version).await?,
+        None => storage.get(id).await?,
+    };
+    let row = row.ok_or_else(|| anyhow!("No state for {}", id))?;
+
+    if let Some(lock) = storage.get_lock_by_terraform_id(id).await? {
+        eprintln!("{} is locked by {} since {}: {}", id, lock.id, lock.last_update_ts, lock.state);
+    }
+
+    println!("{}", pretty(&row.state));
+
+    Ok(())
+}
+
+
+/// Removes a lock, queueing `lock.force-released` webhooks for the server to
+/// deliver
+async fn unlock(storage: &SharedStorage, hooks: &[Webhook], id: &str, force: bool) -> Result<(), anyhow::Error> {
+    if !force {
+        bail!("Unlocking takes the lock from whoever holds it; pass --force to do so");
+    }
+
+    let lock = match storage.force_unlock(id).await? {
+        Some(lock) => lock,
+        None => {
+            println!("{} was not locked", id);
+
+            return Ok(());
+        },
+    };
+
+    println!("Removed lock {} on {} held since {}", lock.id, id, lock.last_update_ts);
+
+    if let Some(pool) = storage.pool() {
+        let event = Event::new(EventKind::LockForceReleased, id)
+            .with_actor(&whoami())
+            .with_lock(&lock.state);
+
+        webhooks::enqueue(pool, hooks, &event)
+            .await?;
+    }
+
+    Ok(())
+}
+
+
+async fn export(storage: &SharedStorage, id: &str, output: Option<&Path>) -> Result<(), anyhow::Error> {
+    let row = storage
+        .get(id)
+        .await?
+        .ok_or_else(|| anyhow!("No state for {}", id))?;
+
+    match output {
+        Some(path) => fs::write(path, &row.state)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => io::stdout()
+            .write_all(row.state.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+
+async fn import(importer: &Importer, id: &str, file: &Path, force: bool) -> Result<(), anyhow::Error> {
+    let storage = &importer.storage;
+    let contents = fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let document: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("{} is not JSON", file.display()))?;
+
+    if let Some(lock) = storage.get_lock_by_terraform_id(id).await? {
+        if !force {
+            bail!("{} is locked by {}; pass --force to replace its state anyway", id, lock.id);
+        }
+    }
+
+    let state = importer
+        .admission()
+        .check(storage, id, &document)
+        .await
+        .with_context(|| format!("Refusing to import {}", file.display()))?;
+
+    storage.create_or_replace(id, &document.to_string())
+        .await?;
+
+    importer.inventory.update(id, &state);
+
+    println!("Imported {} into {}", file.display(), id);
+
+    importer.written(&[id])
+        .await
+}
+
+
+async fn backup(config: &Configuration, path: Option<&Path>) -> Result<(), anyhow::Error> {
+    let pool = database::get_db_pool(config)
+        .await
+        .context("Failed to open database")?;
+
+    let path = match (path, &config.backup_dir) {
+        (Some(path), _) => {
+            database::backup(&pool, path)
+                .await
+                .with_context(|| format!("Failed to back up to {}", path.display()))?;
+
+            path.to_path_buf()
+        },
+        (None, Some(dir)) => backup::create(&pool, Path::new(dir), config.backup_retain)
+            .await?,
+        (None, None) => bail!("Give a backup path or set BACKUP_DIR"),
+    };
+
+    println!("Backed up {} to {}", config.database_uri, path.display());
+
+    Ok(())
+}
+
+
+async fn restore(config: &Configuration, backup: &Path) -> Result<(), anyhow::Error> {
+    let database = database::database_path(config)
+        .ok_or_else(|| anyhow!("{} is not a database file", config.database_uri))?;
+
+    let previous = backup::restore(backup, &database)
+        .await?;
+
+    println!("Restored {} from {}; the previous database is at {}", database.display(), backup.display(), previous.display());
+
+    Ok(())
+}
+
+
+async fn export_archive(storage: &SharedStorage, path: &Path) -> Result<(), anyhow::Error> {
+    let file = fs::File::create(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let manifest = archive::export(storage, io::BufWriter::new(file))
+        .await?;
+    let versions: usize = manifest
+        .workspaces
+        .iter()
+        .map(|workspace| workspace.versions.len())
+        .sum();
+
+    println!("Exported {} states and {} versions to {}", manifest.workspaces.len(), versions, path.display());
+
+    Ok(())
+}
+
+
+async fn import_archive(importer: &Importer, path: &Path) -> Result<(), anyhow::Error> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let report = archive::import(&importer.storage, io::BufReader::new(file), &importer.admission())
+        .await?;
+
+    for (id, outcome) in report.iter() {
+        println!("{}\t{}", id, outcome);
+    }
+
+    let written: Vec<_> = report
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, ImportOutcome::Created { .. } | ImportOutcome::Updated { .. }))
+        .map(|(id, _)| id.as_str())
+        .collect();
+
+    importer.written(&written)
+        .await?;
+
+    let failures = report
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, ImportOutcome::Conflict(_) | ImportOutcome::Rejected(_)))
+        .count();
+
+    if failures > 0 {
+        bail!("{} states conflict with or were rejected from the archive and were not imported", failures);
+    }
+
+    Ok(())
+}
+
+
+async fn import_local(importer: &Importer, dir: &Path, template: &str, apply: bool) -> Result<(), anyhow::Error> {
+    let admission = importer.admission();
+    let candidates = local_backend::plan(&importer.storage, dir, template, &admission)
+        .await?;
+
+    for candidate in candidates.iter() {
+        println!("{}\t{}\t{}", candidate.path.display(), candidate.id, candidate.action);
+    }
+
+    let writes = candidates
+        .iter()
+        .filter(|candidate| candidate.action.writes())
+        .count();
+    let rejected = candidates
+        .iter()
+        .filter(|candidate| matches!(candidate.action, Action::Conflict(_) | Action::Invalid(_)))
+        .count();
+
+    if !apply {
+        println!("Dry run: {} states would be written and {} rejected; pass --apply to import them", writes, rejected);
+
+        return Ok(());
+    }
+
+    let written = local_backend::apply(&importer.storage, &candidates, &admission)
+        .await?;
+
+    println!("Imported {} states", written);
+
+    let ids: Vec<_> = candidates
+        .iter()
+        .filter(|candidate| candidate.action.writes())
+        .map(|candidate| candidate.id.as_str())
+        .collect();
+
+    importer.written(&ids)
+        .await?;
+
+    if rejected > 0 {
+        bail!("{} states were rejected and not imported", rejected);
+    }
+
+    Ok(())
+}
+
+
+/// Reads back every state and version, reporting any that fail to decrypt,
+/// decompress or parse
+async fn verify(storage: &SharedStorage) -> Result<(), anyhow::Error> {
+    let mut checked = 0;
+    let mut failures = 0;
+
+    for state in storage.list().await? {
+        let mut check = |what: String, result: Result<Option<String>, String>| {
+            checked += 1;
+
+            let error = match result {
+                Ok(Some(body)) => serde_json::from_str::<Value>(&body).err().map(|e| e.to_string()),
+                Ok(None) => Some("missing".to_string()),
+                Err(e) => Some(e),
+            };
+
+            if let Some(error) = error {
+                failures += 1;
+                eprintln!("{}: {}", what, error);
+            }
+        };
+
+        let current = storage
+            .get(&state.id)
+            .await
+            .map(|row| row.map(|row| row.state))
+            .map_err(|e| e.to_string());
+
+        check(state.id.clone(), current);
+
+        for version in storage.list_versions(&state.id).await? {
+            let body = storage
+                .get_version(&state.id, version.version)
+                .await
+                .map(|row| row.map(|row| row.state))
+                .map_err(|e| e.to_string());
+
+            check(format!("{}@{}", state.id, version.version), body);
+        }
+    }
+
+    println!("Checked {} states and versions, {} failed", checked, failures);
+
+    if failures > 0 {
+        bail!("{} states or versions failed verification", failures);
+    }
+
+    Ok(())
+}
+
+
+/// Checks a state file against the write rules as a POST to `workspace` would
+fn check_rules(config: &Configuration, workspace: &str, state: &Path, previous: Option<&Path>, rules: Option<&Path>) -> Result<(), anyhow::Error> {
+    let rules = match rules {
+        Some(path) => Rule::from_file(path)?,
+        None => Rule::from_config(config)?,
+    };
+
+    if rules.is_empty() {
+        bail!("No rules to check; set RULES_FILE or pass --rules");
+    }
+
+    let read_state = |path: &Path| -> Result<State, anyhow::Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let value: Value = serde_json::from_str(&contents)
+            .with_context(|| format!("{} is not JSON", path.display()))?;
+
+        State::from_value(&value)
+            .map_err(|e| anyhow!("{} is not a valid state: {}", path.display(), e))
+    };
+
+    let state = read_state(state)?;
+    let previous = previous
+        .map(read_state)
+        .transpose()?;
+    let violations = rules::evaluate(&rules, workspace, previous.as_ref(), &state);
+
+    for violation in violations.iter() {
+        println!("{}", violation);
+    }
+
+    if !violations.is_empty() {
+        bail!("{} rule violations", violations.len());
+    }
+
+    println!("No rule violations");
+
+    Ok(())
+}
+
+
+/// Prints a new bearer token and the `AUTH_FILE` entry for it; only the hash
+/// is kept, so the token cannot be shown again
+fn create_token(name: &str) -> Result<(), anyhow::Error> {
+    let mut bytes = [0u8; 32];
+
+    OsRng.fill_bytes(&mut bytes);
+
+    let token = hex::encode(bytes);
+
+    println!("Token: {}", token);
+    println!();
+    println!("Add to AUTH_FILE, along with an ACL granting {} permissions:", name);
+    println!();
+    println!("[[tokens]]");
+    println!("name = {:?}", name);
+    println!("sha256 = {:?}", sha256_hex(&token));
+
+    Ok(())
+}
+
+
+/// Reads a password from stdin and prints the `AUTH_FILE` entry for a user
+/// with it
+fn hash_password(name: &str) -> Result<(), anyhow::Error> {
+    let mut password = String::new();
+
+    io::stdin()
+        .read_line(&mut password)
+        .context("Failed to read the password from stdin")?;
+
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+
+    if password.is_empty() {
+        bail!("Pass the password on stdin");
+    }
+
+    let hash = auth::hash_password(password)
+        .map_err(|e| anyhow!("Failed to hash the password: {}", e))?;
+
+    println!("Add to AUTH_FILE, along with an ACL granting {} permissions:", name);
+    println!();
+    println!("[[users]]");
+    println!("name = {:?}", name);
+    println!("password_hash = {:?}", hash);
+
+    Ok(())
+}
+
+
+/// Re-encrypts every state under the primary encryption key; safe to run
+/// while the server is serving requests
+async fn rekey(config: &Configuration) -> Result<(), anyhow::Error> {
+    let keyring = Keyring::from_config(config)
+        .map_err(|e| anyhow!("Failed to load encryption keys: {}", e.0))?
+        .ok_or_else(|| anyhow!("ENCRYPTION_KEYS or ENCRYPTION_KEYS_FILE must be set to rekey"))?;
+
+    let database = open_database(config)
+        .await?;
+
+    let primary = keyring
+        .primary_key_id()
+        .to_string();
+    let rewritten = TerraformQuery::new(database)
+        .with_keyring(Some(Arc::new(keyring)))
+        .rekey()
+        .await?;
+
+    println!("Re-encrypted {} states under key {}", rewritten, primary);
+
+    Ok(())
+}
+
+
+/// Re-encodes every state and version not yet stored with the configured
+/// codec; safe to run while the server is serving requests
+async fn recompress(config: &Configuration) -> Result<(), anyhow::Error> {
+    if config.storage_backend != StorageBackend::Sqlite {
+        bail!("Only the sqlite storage backend compresses states");
+    }
+
+    let keyring = Keyring::from_config(config)
+        .map_err(|e| anyhow!("Failed to load encryption keys: {}", e.0))?;
+
+    let database = open_database(config)
+        .await?;
+
+    let rewritten = TerraformQuery::new(database)
+        .with_keyring(keyring.map(Arc::new))
+        .with_codec(config.state_compression)
+        .recompress()
+        .await?;
+
+    println!("Re-encoded {} states with the {} codec", rewritten, config.state_compression);
+
+    Ok(())
+}
+
+
+/// The operator running a command, for the `actor` of the events it causes
+fn whoami() -> String {
+    std::env::var("USER")
+        .map(|user| format!("cli:{}", user))
+        .unwrap_or_else(|_| "cli".to_string())
+}
+
+
+fn pretty(state: &str) -> String {
+    serde_json::from_str::<Value>(state)
+        .ok()
+        .and_then(|value| serde_json::to_string_pretty(&value).ok())
+        .unwrap_or_else(|| state.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::Arc,
+    };
+
+    use tokio;
+
+    use super::{
+        Importer,
+        import,
+        unlock,
+        verify,
+    };
+    use crate::{
+        inventory::{
+            DuplicatePolicy,
+            Inventory,
+            VersionFilter,
+        },
+        rules::RulesMode,
+        storage::{
+            MemoryStorage,
+            SharedStorage,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_import_unlock_verify() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let importer = Importer {
+            storage: storage.clone(),
+            rules: Vec::new(),
+            rules_mode: RulesMode::Enforce,
+            duplicates: DuplicatePolicy::Reject,
+            inventory: Inventory::new(),
+            hooks: Vec::new(),
+        };
+        let file = std::env::temp_dir()
+            .join(format!("tf-http-backend-import-{}.tfstate", std::process::id()));
+        let id = "105";
+        let state = "{\"lineage\":\"abcd\",\"serial\":1,\"terraform_version\":\"1.0.0\",\"version\":4}";
+
+        // not a state a POST would accept
+        fs::write(&file, "{\"serial\": 1}")
+            .unwrap();
+
+        assert!(import(&importer, id, &file, false).await.is_err());
+
+        fs::write(&file, state)
+            .unwrap();
+
+        storage.lock(id, "abcd", "{}")
+            .await
+            .unwrap();
+
+        assert!(import(&importer, id, &file, false).await.is_err());
+        assert!(unlock(&storage, &[], id, false).await.is_err());
+
+        unlock(&storage, &[], id, true)
+            .await
+            .unwrap();
+        import(&importer, id, &file, false)
+            .await
+            .unwrap();
+
+        assert_eq!(storage.get(id).await.unwrap().unwrap().state, state);
+        assert_eq!(importer.inventory.versions(&VersionFilter::default()).len(), 1);
+
+        verify(&storage)
+            .await
+            .unwrap();
+
+        storage.create_or_replace(id, "not json")
+            .await
+            .unwrap();
+
+        assert!(verify(&storage).await.is_err());
+    }
+}
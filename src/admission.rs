@@ -0,0 +1,126 @@
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::{
+    config::Settings,
+    inventory::{
+        Duplicate,
+        DuplicatePolicy,
+        Inventory,
+    },
+    models::state::{
+        InvalidState,
+        State,
+    },
+    rules::{
+        self,
+        Rule,
+        RulesMode,
+        Violation,
+    },
+    storage::{
+        SharedStorage,
+        StorageError,
+    },
+};
+
+
+/// The checks a state must pass before it replaces a workspace's state,
+/// whether it is posted by terraform or imported by an admin command
+pub struct Admission<'a> {
+    pub rules: &'a [Rule],
+    pub rules_mode: RulesMode,
+    pub duplicates: DuplicatePolicy,
+    pub inventory: &'a Inventory,
+}
+
+
+/// Why a state may not be written
+#[derive(Debug)]
+pub enum Rejection {
+    Invalid(InvalidState),
+    Rules(Vec<Violation>),
+    Duplicates(Vec<Duplicate>),
+    Storage(StorageError),
+}
+
+
+impl<'a> Admission<'a> {
+    pub fn new(settings: &'a Settings, inventory: &'a Inventory) -> Self {
+        Self {
+            rules: &settings.rules,
+            rules_mode: settings.config.rules_mode,
+            duplicates: settings.config.duplicate_resources,
+            inventory,
+        }
+    }
+
+    /// Checks a document about to be written to `workspace`, returning it as
+    /// a state. Rule violations in dry-run mode and duplicates in warn mode
+    /// are logged rather than rejected.
+    pub async fn check(&self, storage: &SharedStorage, workspace: &str, document: &Value) -> Result<State, Rejection> {
+        let state = State::from_value(document)
+            .map_err(Rejection::Invalid)?;
+
+        if !self.rules.is_empty() {
+            // a stored document that is not a state has nothing to protect
+            let previous = storage.get(workspace)
+                .await?
+                .and_then(|row| serde_json::from_str(&row.state).ok())
+                .and_then(|previous: Value| State::from_value(&previous).ok());
+            let violations = rules::evaluate(self.rules, workspace, previous.as_ref(), &state);
+
+            if !violations.is_empty() {
+                match self.rules_mode {
+                    RulesMode::DryRun => tracing::warn!("State written to {} violates rules: {}", workspace, join(&violations)),
+                    RulesMode::Enforce => return Err(Rejection::Rules(violations)),
+                }
+            }
+        }
+
+        let duplicates = self.inventory
+            .duplicates_of(workspace, &state);
+
+        if !duplicates.is_empty() {
+            match self.duplicates {
+                DuplicatePolicy::Allow => (),
+                DuplicatePolicy::Warn => tracing::warn!("State written to {} duplicates other workspaces: {}", workspace, join(&duplicates)),
+                DuplicatePolicy::Reject => return Err(Rejection::Duplicates(duplicates)),
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(e) => write!(f, "Invalid state: {}", e),
+            Self::Rules(violations) => write!(f, "State violates rules: {}", join(violations)),
+            Self::Duplicates(duplicates) => write!(f, "State manages objects other workspaces already manage: {}", join(duplicates)),
+            Self::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+
+impl std::error::Error for Rejection {}
+
+
+impl From<StorageError> for Rejection {
+    fn from(e: StorageError) -> Self {
+        Self::Storage(e)
+    }
+}
+
+
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
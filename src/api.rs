@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use axum::{
     Router,
     routing::{
@@ -5,7 +7,6 @@ use axum::{
         post,
     },
 };
-use sqlx::SqlitePool;
 use tower::ServiceBuilder;
 use tower_http::{
     add_extension::AddExtensionLayer,
@@ -14,34 +15,94 @@ use tower_http::{
 
 use crate::{
     config::SharedConfiguration,
-    routes::terraform::{
-        TerraformLockRoute,
-        TerraformRoute,
+    events::{
+        Events,
+        SharedEvents,
+    },
+    inventory::{
+        Inventory,
+        SharedInventory,
+    },
+    lifecycle::{
+        InFlightLayer,
+        Lifecycle,
+        SharedLifecycle,
     },
+    metrics::{
+        Metrics,
+        MetricsLayer,
+        SharedMetrics,
+    },
+    routes::{
+        admin::AdminBackupRoute,
+        duplicates::DuplicatesRoute,
+        events::EventsRoute,
+        health::HealthRoute,
+        inventory::InventoryRoute,
+        metrics::MetricsRoute,
+        search::SearchRoute,
+        terraform::{
+            TerraformFindingsRoute,
+            TerraformLockRoute,
+            TerraformOutputsRoute,
+            TerraformRoute,
+            TerraformVersionRoute,
+        },
+    },
+    storage::SharedStorage,
 };
 
 
 pub struct Api {
     config: SharedConfiguration,
-    pool: SqlitePool,
+    storage: SharedStorage,
+    metrics: SharedMetrics,
+    lifecycle: SharedLifecycle,
+    events: SharedEvents,
+    inventory: SharedInventory,
 }
 
 
 impl Api {
-    pub fn new(config: SharedConfiguration, pool: SqlitePool) -> Self {
+    pub fn new(config: SharedConfiguration, storage: SharedStorage) -> Self {
+        let events = Events::new(config.clone(), storage.pool().cloned());
+
         Self {
             config,
-            pool
+            storage,
+            metrics: Arc::new(Metrics::new()),
+            lifecycle: Arc::new(Lifecycle::new()),
+            events: Arc::new(events),
+            inventory: Arc::new(Inventory::new()),
         }
     }
+
+    /// Handle used to mark the server as draining once the router is built
+    pub fn lifecycle(&self) -> SharedLifecycle {
+        self.lifecycle.clone()
+    }
+
+    /// Handle used to deliver the events the API publishes
+    pub fn events(&self) -> SharedEvents {
+        self.events.clone()
+    }
+
+    /// Handle used to index the stored states before serving searches
+    pub fn inventory(&self) -> SharedInventory {
+        self.inventory.clone()
+    }
 }
 
 
-impl Into<Router> for Api {
-    fn into(self) -> Router {
+impl From<Api> for Router {
+    fn from(api: Api) -> Self {
         let layer = ServiceBuilder::new()
-            .layer(AddExtensionLayer::new(self.config))
-            .layer(AddExtensionLayer::new(self.pool))
+            .layer(AddExtensionLayer::new(api.config))
+            .layer(AddExtensionLayer::new(api.storage))
+            .layer(AddExtensionLayer::new(api.metrics.clone()))
+            .layer(AddExtensionLayer::new(api.lifecycle.clone()))
+            .layer(AddExtensionLayer::new(api.events))
+            .layer(AddExtensionLayer::new(api.inventory))
             .into_inner();
 
         let tf_service = get(TerraformRoute::get)
@@ -53,6 +114,20 @@ impl Into<Router> for Api {
         Router::new()
             .route("/terraform/:id", tf_service)
             .route("/terraform/:id/lock", tf_lock_service)
+            .route("/terraform/:id/outputs", get(TerraformOutputsRoute::get))
+            .route("/terraform/:id/findings", get(TerraformFindingsRoute::get))
+            .route("/terraform/:id/versions", get(TerraformVersionRoute::list))
+            .route("/terraform/:id/versions/:version", get(TerraformVersionRoute::get))
+            .route("/events", get(EventsRoute::get))
+            .route("/search", get(SearchRoute::get))
+            .route("/duplicates", get(DuplicatesRoute::get))
+            .route("/inventory/versions", get(InventoryRoute::versions))
+            .route("/admin/backups", post(AdminBackupRoute::post))
+            .route("/metrics", get(MetricsRoute::get))
+            .route("/healthz", get(HealthRoute::live))
+            .route("/readyz", get(HealthRoute::ready))
+            .layer(MetricsLayer::new(api.metrics))
+            .layer(InFlightLayer::new(api.lifecycle))
             .layer(TraceLayer::new_for_http())
             .layer(layer)
     }
@@ -0,0 +1,464 @@
+use std::{
+    fmt,
+    path::Path,
+};
+
+use argon2::{
+    Argon2,
+    PasswordHash,
+    PasswordHasher,
+    PasswordVerifier,
+    password_hash::{
+        self,
+        SaltString,
+        rand_core::OsRng,
+    },
+};
+use serde::Deserialize;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::{
+        self,
+        ConfigError,
+        Configuration,
+    },
+    error::HttpError,
+};
+
+
+/// What a principal may do to a workspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// Read the state and its history
+    Read,
+    /// Read only the root module outputs of the state; implied by `Read`
+    OutputsRead,
+    /// Read the state with sensitive outputs and attributes blanked; implied
+    /// by `Read`
+    View,
+    /// See the values of outputs marked sensitive when the outputs API
+    /// formats them, which are otherwise masked; only ever granted explicitly
+    SensitiveOutputs,
+    /// Replace the state
+    Write,
+    /// Acquire and release the workspace lock
+    Lock,
+    /// Every permission, including administrative endpoints
+    Admin,
+}
+
+
+/// Users, API tokens and the ACLs granting them permissions, built from
+/// `TF_HTTP_USERNAME`/`TF_HTTP_PASSWORD` and the optional `AUTH_FILE`
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// `TF_HTTP_USERNAME` and `TF_HTTP_PASSWORD`, which the environment holds
+    /// in the clear anyway
+    env_user: (String, String),
+    users: Vec<User>,
+    tokens: Vec<Token>,
+    acls: Vec<Acl>,
+}
+
+
+/// An authenticated caller and the ACL entries that apply to them
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    grants: Vec<Acl>,
+}
+
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthFile {
+    #[serde(default)]
+    users: Vec<User>,
+    #[serde(default)]
+    tokens: Vec<Token>,
+    #[serde(default)]
+    acls: Vec<Acl>,
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct User {
+    name: String,
+    /// Argon2 hash of the password in the PHC string format, as printed by
+    /// the `hash-password` command
+    password_hash: String,
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Token {
+    name: String,
+    /// Hex encoded SHA-256 of the bearer token
+    sha256: String,
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Acl {
+    /// User or token names; `*` matches any authenticated principal
+    principals: Vec<String>,
+    /// Workspace id patterns where `*` matches any run of characters
+    workspaces: Vec<String>,
+    permissions: Vec<Permission>,
+}
+
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::OutputsRead => "outputs-read",
+            Self::View => "view",
+            Self::SensitiveOutputs => "sensitive-outputs",
+            Self::Write => "write",
+            Self::Lock => "lock",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// Whether holding this permission grants `other`
+    pub fn implies(&self, other: Permission) -> bool {
+        match (self, other) {
+            (Self::Admin, _) => true,
+            (Self::Read, Self::OutputsRead | Self::View) => true,
+            (granted, other) => *granted == other,
+        }
+    }
+}
+
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+
+impl Policy {
+    pub fn from_config(config: &Configuration) -> Result<Self, ConfigError> {
+        let file = match &config.auth_file {
+            Some(path) => config::parse_file::<AuthFile>(Path::new(path))?,
+            None => AuthFile::default(),
+        };
+
+        // the user from the environment predates ACLs and may do anything
+        let mut acls = vec![Acl {
+            principals: vec![config.tf_http_username.clone()],
+            workspaces: vec!["*".to_string()],
+            permissions: vec![Permission::Admin],
+        }];
+
+        acls.extend(file.acls);
+
+        for user in file.users.iter() {
+            match PasswordHash::new(&user.password_hash) {
+                Ok(hash) if hash.algorithm.as_str().starts_with("argon2") => (),
+                _ => return Err(ConfigError::Invalid(format!("The password_hash of {} is not an argon2 hash", user.name))),
+            }
+        }
+
+        for token in file.tokens.iter() {
+            if hex::decode(&token.sha256).map(|digest| digest.len()) != Ok(Sha256::output_size()) {
+                return Err(ConfigError::Invalid(format!("The sha256 of {} is not a hex encoded SHA-256", token.name)));
+            }
+        }
+
+        // ACLs name principals, so a name must identify exactly one of them
+        let mut names: Vec<_> = file.users
+            .iter()
+            .map(|user| &user.name)
+            .chain(file.tokens.iter().map(|token| &token.name))
+            .chain(Some(&config.tf_http_username))
+            .collect();
+
+        names.sort();
+
+        if let Some(pair) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(ConfigError::Invalid(format!("Principal {} is defined more than once", pair[0])));
+        }
+
+        Ok(Self {
+            env_user: (config.tf_http_username.clone(), config.tf_http_password.clone()),
+            users: file.users,
+            tokens: file.tokens,
+            acls,
+        })
+    }
+
+    /// Returns the user with the given name and password. Passwords are
+    /// compared in constant time, or verified against their argon2 hash for
+    /// users from `AUTH_FILE`.
+    pub fn authenticate_basic(&self, name: &str, password: &str) -> Option<Principal> {
+        let (env_name, env_password) = &self.env_user;
+
+        let authenticated = if name == env_name {
+            // digests have the same length whatever the passwords' lengths
+            Sha256::digest(password.as_bytes())
+                .ct_eq(&Sha256::digest(env_password.as_bytes()))
+                .into()
+        } else {
+            self.users
+                .iter()
+                .find(|user| user.name == name)
+                .and_then(|user| PasswordHash::new(&user.password_hash).ok())
+                .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+                .unwrap_or(false)
+        };
+
+        if authenticated {
+            Some(self.principal(name))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the token matching a bearer token. Tokens are random, so a
+    /// plain SHA-256 of them is as good as a password hash.
+    pub fn authenticate_token(&self, token: &str) -> Option<Principal> {
+        let hash = Sha256::digest(token.as_bytes());
+
+        self.tokens
+            .iter()
+            .find(|candidate| {
+                hex::decode(&candidate.sha256)
+                    .map(|digest| digest.as_slice().ct_eq(hash.as_slice()).into())
+                    .unwrap_or(false)
+            })
+            .map(|token| self.principal(&token.name))
+    }
+
+    fn principal(&self, name: &str) -> Principal {
+        let grants = self.acls
+            .iter()
+            .filter(|acl| acl.principals.iter().any(|principal| principal == "*" || principal == name))
+            .cloned()
+            .collect();
+
+        Principal {
+            name: name.to_string(),
+            grants,
+        }
+    }
+}
+
+
+impl Principal {
+    pub fn allows(&self, workspace: &str, permission: Permission) -> bool {
+        self.grants
+            .iter()
+            .filter(|acl| acl.workspaces.iter().any(|pattern| glob_match(pattern, workspace)))
+            .flat_map(|acl| acl.permissions.iter())
+            .any(|granted| granted.implies(permission))
+    }
+
+    /// Fails with 403 unless the principal holds `permission` on `workspace`
+    pub fn authorize(&self, workspace: &str, permission: Permission) -> Result<(), HttpError> {
+        if self.allows(workspace, permission) {
+            Ok(())
+        } else {
+            Err(HttpError::forbidden(Some(format!("{} lacks the {} permission on {}", self.name, permission, workspace))))
+        }
+    }
+}
+
+
+pub fn sha256_hex(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+
+/// Hashes a password with argon2 and a random salt into the PHC string that
+/// `AUTH_FILE` users hold
+pub fn hash_password(password: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?;
+
+    Ok(hash.to_string())
+}
+
+
+/// Matches `value` against `pattern`, where `*` matches any run of characters
+pub(crate) fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts
+        .next()
+        .unwrap_or_default();
+
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<_> = parts.collect();
+
+    match parts.split_last() {
+        // no wildcard
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(index) => rest = &rest[index + part.len()..],
+                    None => return false,
+                }
+            }
+
+            rest.len() >= last.len() && rest.ends_with(last)
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs,
+    };
+
+    use argon2::{
+        Algorithm,
+        Argon2,
+        Params,
+        PasswordHasher,
+        Version,
+        password_hash::{
+            SaltString,
+            rand_core::OsRng,
+        },
+    };
+    use envconfig::Envconfig;
+
+    use super::{
+        Permission,
+        Policy,
+        glob_match,
+        hash_password,
+        sha256_hex,
+    };
+    use crate::config::Configuration;
+
+    /// An argon2 hash with parameters cheap enough for unoptimised tests
+    fn cheap_hash(password: &str) -> String {
+        let params = Params::new(8, 1, 1, None)
+            .unwrap();
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string()
+    }
+
+    fn config_with_auth_file(contents: &str) -> Configuration {
+        let path = std::env::temp_dir()
+            .join(format!("tf-http-backend-auth-{}-{}.toml", std::process::id(), sha256_hex(contents)));
+
+        fs::write(&path, contents)
+            .unwrap();
+
+        let mut hashmap = HashMap::new();
+
+        hashmap.insert("DATABASE_URI".to_string(), "memory://".to_string());
+        hashmap.insert("TF_HTTP_USERNAME".to_string(), "asdf".to_string());
+        hashmap.insert("TF_HTTP_PASSWORD".to_string(), "asdf".to_string());
+        hashmap.insert("AUTH_FILE".to_string(), path.to_str().unwrap().to_string());
+
+        Configuration::init_from_hashmap(&hashmap)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("prod-*", "prod-network"));
+        assert!(glob_match("*-network", "prod-network"));
+        assert!(glob_match("prod-*-east", "prod-network-east"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("prod-*", "staging-network"));
+        assert!(!glob_match("a*a", "a"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_policy() {
+        let config = config_with_auth_file(&format!(
+            "[[users]]\nname = \"ci\"\npassword_hash = \"{}\"\n\n\
+            [[tokens]]\nname = \"dashboard\"\nsha256 = \"{}\"\n\n\
+            [[acls]]\nprincipals = [\"ci\"]\nworkspaces = [\"prod-*\"]\npermissions = [\"read\", \"lock\", \"write\"]\n\n\
+            [[acls]]\nprincipals = [\"*\"]\nworkspaces = [\"*\"]\npermissions = [\"read\"]\n",
+            cheap_hash("ci-password"),
+            sha256_hex("dashboard-token"),
+        ));
+        let policy = Policy::from_config(&config)
+            .unwrap();
+
+        let admin = policy.authenticate_basic("asdf", "asdf")
+            .expect("Legacy user should authenticate");
+        let ci = policy.authenticate_basic("ci", "ci-password")
+            .expect("File user should authenticate");
+        let dashboard = policy.authenticate_token("dashboard-token")
+            .expect("Token should authenticate");
+
+        assert!(policy.authenticate_basic("ci", "wrong").is_none());
+        assert!(policy.authenticate_basic("asdf", "asdfg").is_none());
+        assert!(policy.authenticate_basic("nobody", "ci-password").is_none());
+        assert!(policy.authenticate_token("wrong").is_none());
+
+        assert!(admin.allows("anything", Permission::Admin));
+        assert!(ci.allows("prod-network", Permission::Write));
+        assert!(!ci.allows("staging-network", Permission::Write));
+        assert!(ci.allows("staging-network", Permission::Read));
+        assert!(dashboard.allows("prod-network", Permission::Read));
+        assert!(!dashboard.allows("prod-network", Permission::Lock));
+        assert!(dashboard.authorize("prod-network", Permission::Lock).is_err());
+        assert!(dashboard.allows("prod-network", Permission::OutputsRead));
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("ci-password")
+            .unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_password("ci-password").unwrap());
+    }
+
+    #[test]
+    fn test_permission_implies() {
+        assert!(Permission::Admin.implies(Permission::OutputsRead));
+        assert!(Permission::Read.implies(Permission::OutputsRead));
+        assert!(!Permission::OutputsRead.implies(Permission::Read));
+        assert!(Permission::Read.implies(Permission::View));
+        assert!(!Permission::View.implies(Permission::Read));
+        assert!(!Permission::Write.implies(Permission::Read));
+        assert!(!Permission::Read.implies(Permission::SensitiveOutputs));
+        assert!(Permission::Admin.implies(Permission::SensitiveOutputs));
+    }
+
+    #[test]
+    fn test_policy_invalid() {
+        let duplicate = config_with_auth_file(&format!("[[users]]\nname = \"asdf\"\npassword_hash = \"{}\"\n", cheap_hash("x")));
+        let unknown = config_with_auth_file("[[acls]]\nprincipals = [\"*\"]\nworkspaces = [\"*\"]\npermissions = [\"delete\"]\n");
+        let unsalted = config_with_auth_file(&format!("[[users]]\nname = \"ci\"\npassword_hash = \"{}\"\n", sha256_hex("x")));
+        let short_token = config_with_auth_file("[[tokens]]\nname = \"ci\"\nsha256 = \"abcd\"\n");
+
+        assert!(Policy::from_config(&duplicate).is_err());
+        assert!(Policy::from_config(&unknown).is_err());
+        assert!(Policy::from_config(&unsalted).is_err());
+        assert!(Policy::from_config(&short_token).is_err());
+    }
+}
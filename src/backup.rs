@@ -0,0 +1,302 @@
+use std::{
+    fmt,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::Utc;
+use sqlx::{
+    ConnectOptions,
+    SqlitePool,
+    sqlite::{
+        SqliteConnectOptions,
+        SqliteJournalMode,
+    },
+};
+
+use crate::database;
+
+
+/// Backup files are named `<prefix><timestamp><suffix>` so retention only
+/// ever removes files this module wrote
+const BACKUP_PREFIX: &str = "tf_state-";
+const BACKUP_SUFFIX: &str = ".db";
+
+
+#[derive(Debug)]
+pub struct BackupError(pub String);
+
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Backup error: {}", self.0)
+    }
+}
+
+
+impl std::error::Error for BackupError {}
+
+
+impl From<sqlx::Error> for BackupError {
+    fn from(e: sqlx::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+
+impl From<std::io::Error> for BackupError {
+    fn from(e: std::io::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+
+/// Writes a timestamped backup into `dir`, then removes all but the newest
+/// `retain` backups there
+pub async fn create(pool: &SqlitePool, dir: &Path, retain: usize) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("{}{}{}", BACKUP_PREFIX, Utc::now().format("%Y%m%dT%H%M%S%.6fZ"), BACKUP_SUFFIX));
+
+    database::backup(pool, &path)
+        .await?;
+
+    // in-memory databases back up into memory rather than to the file
+    if !path.is_file() {
+        return Err(BackupError(format!("{} was not written", path.display())));
+    }
+
+    for expired in list(dir)?.into_iter().rev().skip(retain.max(1)) {
+        fs::remove_file(&expired)?;
+
+        tracing::info!("Removed expired backup {}", expired.display());
+    }
+
+    Ok(path)
+}
+
+
+/// Backups in `dir`, oldest first
+pub fn list(dir: &Path) -> Result<Vec<PathBuf>, BackupError> {
+    let mut backups: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    // timestamps sort lexically
+    backups.sort();
+
+    Ok(backups)
+}
+
+
+/// Takes a backup every `interval`, logging rather than stopping on failure
+pub async fn schedule(pool: SqlitePool, dir: PathBuf, interval: Duration, retain: usize) {
+    let mut ticks = tokio::time::interval(interval);
+
+    // the first tick completes immediately; wait a full interval instead
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+
+        match create(&pool, &dir, retain).await {
+            Ok(path) => tracing::info!("Wrote scheduled backup {}", path.display()),
+            Err(e) => tracing::error!("Scheduled backup failed: {}", e),
+        }
+    }
+}
+
+
+/// Checks that `path` is an intact database written by this server
+pub async fn check(path: &Path) -> Result<(), BackupError> {
+    if !path.is_file() {
+        return Err(BackupError(format!("{} does not exist", path.display())));
+    }
+
+    let mut connection = SqliteConnectOptions::from_str(&path.to_string_lossy())?
+        .read_only(true)
+        // VACUUM INTO writes rollback journal databases, and switching to
+        // WAL as the default options do would need write access
+        .journal_mode(SqliteJournalMode::Delete)
+        .connect()
+        .await?;
+
+    let results: Vec<(String,)> = sqlx::query_as("PRAGMA integrity_check")
+        .fetch_all(&mut connection)
+        .await?;
+
+    if results.iter().any(|(result,)| result != "ok") {
+        let problems: Vec<_> = results
+            .into_iter()
+            .map(|(result,)| result)
+            .collect();
+
+        return Err(BackupError(format!("{} failed the integrity check: {}", path.display(), problems.join("; "))));
+    }
+
+    let (migrations,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'")
+        .fetch_one(&mut connection)
+        .await?;
+
+    if migrations == 0 {
+        return Err(BackupError(format!("{} is not a terraform-http-backend database", path.display())));
+    }
+
+    Ok(())
+}
+
+
+/// Replaces the database at `database` with a checked copy of `backup`. The
+/// server must be stopped. The replaced database and its WAL are kept beside
+/// it with a timestamped `.pre-restore` suffix, whose path is returned.
+pub async fn restore(backup: &Path, database: &Path) -> Result<PathBuf, BackupError> {
+    check(backup)
+        .await?;
+
+    let staged = suffixed(database, ".restoring");
+    let previous = suffixed(database, &format!(".pre-restore-{}", Utc::now().format("%Y%m%dT%H%M%SZ")));
+
+    // copy next to the database first so the final swap is a rename
+    fs::copy(backup, &staged)?;
+    fs::File::open(&staged)?
+        .sync_all()?;
+
+    if database.exists() {
+        fs::rename(database, &previous)?;
+    }
+
+    // a leftover WAL would be replayed into the restored database
+    for suffix in ["-wal", "-shm"] {
+        let sidecar = suffixed(database, suffix);
+
+        if sidecar.exists() {
+            fs::rename(&sidecar, suffixed(&previous, suffix))?;
+        }
+    }
+
+    fs::rename(&staged, database)?;
+
+    Ok(previous)
+}
+
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path
+        .as_os_str()
+        .to_owned();
+
+    name.push(suffix);
+
+    PathBuf::from(name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        str::FromStr,
+    };
+
+    use sqlx::{
+        SqlitePool,
+        sqlite::SqliteConnectOptions,
+    };
+    use tokio;
+
+    use super::{
+        check,
+        create,
+        list,
+        restore,
+    };
+    use crate::{
+        database,
+        db::terraform::TerraformQuery,
+    };
+
+    async fn file_pool(path: &std::path::Path) -> SqlitePool {
+        let opts = SqliteConnectOptions::from_str(&path.to_string_lossy())
+            .unwrap()
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(opts)
+            .await
+            .unwrap();
+
+        database::MIGRATE.run(&pool)
+            .await
+            .unwrap();
+
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_backup_restore() {
+        let dir = std::env::temp_dir()
+            .join(format!("tf-http-backend-backup-{}", std::process::id()));
+        let backups = dir.join("backups");
+        let database = dir.join("tf_state.db");
+
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir)
+            .unwrap();
+
+        let pool = file_pool(&database)
+            .await;
+        let query = TerraformQuery::new(pool.clone());
+
+        query.create_or_replace("105", "backed-up")
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            create(&pool, &backups, 2)
+                .await
+                .unwrap();
+        }
+
+        let retained = list(&backups)
+            .unwrap();
+
+        assert_eq!(retained.len(), 2);
+
+        query.create_or_replace("105", "after-backup")
+            .await
+            .unwrap();
+        pool.close()
+            .await;
+
+        // not a database
+        fs::write(dir.join("garbage.db"), "garbage")
+            .unwrap();
+        assert!(check(&dir.join("garbage.db")).await.is_err());
+
+        let previous = restore(&retained[1], &database)
+            .await
+            .unwrap();
+
+        assert!(previous.exists());
+
+        let pool = file_pool(&database)
+            .await;
+        let row = TerraformQuery::new(pool)
+            .get("105")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(row.state, "backed-up");
+    }
+}
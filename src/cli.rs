@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+};
+
+use clap::{
+    Args,
+    Parser,
+    Subcommand,
+};
+
+use crate::{
+    config,
+    local_backend,
+};
+
+
+#[derive(Debug, Parser)]
+#[clap(version, about = "Terraform HTTP state backend")]
+pub struct Cli {
+    /// TOML or YAML file of settings; flags and environment variables take
+    /// precedence over it
+    #[clap(long, short, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration and exit
+    #[clap(long)]
+    pub check_config: bool,
+
+    #[clap(flatten)]
+    pub flags: ConfigFlags,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+
+/// Operator commands run against the configured database
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Apply pending database migrations
+    Migrate,
+
+    /// List stored states and their locks
+    List,
+
+    /// Print a state, or one of its versions
+    Show {
+        id: String,
+
+        #[clap(long)]
+        version: Option<i64>,
+    },
+
+    /// Remove the lock on a state whoever holds it
+    Unlock {
+        id: String,
+
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Write a state to a file, or stdout
+    Export {
+        id: String,
+
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Replace a state with the contents of a file, checked as a POST would be
+    Import {
+        id: String,
+
+        file: PathBuf,
+
+        /// Replace the state even if it is locked
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Write a consistent copy of the database while the server runs, to
+    /// PATH or else into BACKUP_DIR
+    Backup {
+        path: Option<PathBuf>,
+    },
+
+    /// Replace the database with a backup after checking its integrity; the
+    /// server must be stopped
+    Restore {
+        backup: PathBuf,
+    },
+
+    /// Write every state and its history to a portable .tar.gz archive
+    ExportArchive {
+        path: PathBuf,
+    },
+
+    /// Load states and history from an archive; states already imported are
+    /// left alone and diverged ones reported as conflicts. Versions are
+    /// written anew, so they are timestamped and numbered by this server
+    /// rather than keeping those recorded in the archive.
+    ImportArchive {
+        path: PathBuf,
+    },
+
+    /// Import every terraform.tfstate and terraform.tfstate.d workspace under
+    /// DIR; only reports what would change unless --apply is given
+    ImportLocal {
+        dir: PathBuf,
+
+        /// Workspace id for each state, from the directory holding it
+        /// relative to DIR with `/` replaced by `-`, and its workspace name
+        #[clap(long, default_value = local_backend::DEFAULT_ID_TEMPLATE)]
+        id_template: String,
+
+        /// Write the states the report says would be created or updated
+        #[clap(long)]
+        apply: bool,
+    },
+
+    /// Check that every state and version can be read back
+    Verify,
+
+    /// Check a state against the rules in RULES_FILE, as a write of it to
+    /// WORKSPACE would be, without a server or database
+    CheckRules {
+        workspace: String,
+
+        /// The state to check
+        state: PathBuf,
+
+        /// The state it replaces, for rules on removed resources
+        #[clap(long)]
+        previous: Option<PathBuf>,
+
+        /// Rules to check instead of those in RULES_FILE
+        #[clap(long)]
+        rules: Option<PathBuf>,
+    },
+
+    /// Generate an API token for AUTH_FILE
+    CreateToken {
+        name: String,
+    },
+
+    /// Hash a password read from stdin for a user in AUTH_FILE
+    HashPassword {
+        name: String,
+    },
+
+    /// Re-encrypt every state under the primary encryption key
+    Rekey,
+
+    /// Re-encode every state and version with the STATE_COMPRESSION codec
+    Recompress,
+}
+
+
+macro_rules! config_flags {
+    (
+        flags { $($flag:ident: $name:literal,)* }
+        secrets { $($secret:ident: $secret_name:literal,)* }
+    ) => {
+        /// Overrides for configuration variables. Secrets have no flag since
+        /// flags are visible to other users; use `*_FILE` for those instead.
+        #[derive(Debug, Default, Args)]
+        #[clap(next_help_heading = "CONFIGURATION")]
+        pub struct ConfigFlags {
+            $(
+                #[clap(long)]
+                pub $flag: Option<String>,
+            )*
+        }
+
+
+        impl ConfigFlags {
+            /// The flags that were given, keyed by variable name
+            pub fn values(&self) -> HashMap<String, String> {
+                let flags = [$(($name, &self.$flag),)*];
+
+                flags
+                    .iter()
+                    .filter_map(|(key, value)| value.as_ref().map(|value| (key.to_string(), value.clone())))
+                    .collect()
+            }
+        }
+    };
+}
+
+config::variables!(config_flags);
+
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{
+        Cli,
+        Command,
+    };
+    use crate::config::{
+        SECRETS,
+        VARIABLES,
+    };
+
+    #[test]
+    fn test_flags() {
+        let cli = Cli::try_parse_from(["server", "--http-port", "9000", "--check-config", "unlock", "105", "--force"])
+            .unwrap();
+        let values = cli.flags.values();
+
+        assert!(cli.check_config);
+        assert!(matches!(cli.command, Some(Command::Unlock { ref id, force: true }) if id == "105"));
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["HTTP_PORT"], "9000");
+        assert!(values.keys().all(|key| VARIABLES.contains(&key.as_str())));
+    }
+    #[test]
+    fn test_every_variable_has_a_flag() {
+        for variable in VARIABLES.iter().filter(|variable| !SECRETS.contains(variable)) {
+            let flag = format!("--{}", variable.to_lowercase().replace('_', "-"));
+            let cli = Cli::try_parse_from(["server", &flag, "x"])
+                .unwrap();
+
+            assert_eq!(cli.flags.values().get(*variable).map(String::as_str), Some("x"), "{}", variable);
+        }
+
+        for secret in SECRETS {
+            let flag = format!("--{}", secret.to_lowercase().replace('_', "-"));
+
+            assert!(Cli::try_parse_from(["server", &flag, "x"]).is_err());
+        }
+    }
+}
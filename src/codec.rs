@@ -0,0 +1,84 @@
+use std::{
+    fmt,
+    io,
+    str::FromStr,
+};
+
+
+const ZSTD_LEVEL: i32 = 3;
+
+
+/// Compression applied to state bodies before they are stored; the codec
+/// name is recorded with each row so rows can be decoded after the
+/// configured codec changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateCodec {
+    Identity,
+    Zstd,
+}
+
+
+impl StateCodec {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    pub fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(body.to_vec()),
+            Self::Zstd => zstd::encode_all(body, ZSTD_LEVEL),
+        }
+    }
+
+    pub fn decode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(body.to_vec()),
+            Self::Zstd => zstd::decode_all(body),
+        }
+    }
+}
+
+
+impl FromStr for StateCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "identity" | "none" => Ok(Self::Identity),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(format!("Unknown state codec: {}", other)),
+        }
+    }
+}
+
+
+impl fmt::Display for StateCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::StateCodec;
+
+    #[test]
+    fn test_round_trip() {
+        let body = serde_json::json!({"resources": vec!["aws_instance"; 100]})
+            .to_string();
+
+        for codec in [StateCodec::Identity, StateCodec::Zstd] {
+            let encoded = codec.encode(body.as_bytes())
+                .unwrap();
+
+            assert_eq!(codec.decode(&encoded).unwrap(), body.as_bytes());
+            assert_eq!(codec.as_str().parse::<StateCodec>().unwrap(), codec);
+        }
+
+        assert!(StateCodec::Zstd.encode(body.as_bytes()).unwrap().len() < body.len());
+    }
+}
@@ -1,14 +1,138 @@
 use std::{
+    collections::HashMap,
+    env,
+    fmt,
+    fs,
     net::IpAddr,
-    sync::Arc,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        RwLock,
+    },
 };
 
 use envconfig::Envconfig;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use tracing_subscriber::EnvFilter;
 
-pub type SharedConfiguration = Arc<Configuration>;
+use crate::{
+    auth::Policy,
+    codec::StateCodec,
+    history::HistoryMode,
+    inventory::DuplicatePolicy,
+    rules::{
+        Rule,
+        RulesMode,
+    },
+    storage::StorageBackend,
+    webhooks::Webhook,
+};
+
+pub type SharedConfiguration = Arc<ConfigurationHandle>;
+
+
+/// Calls `$callback!` with every variable read by `Configuration` as
+/// `field: "NAME"` pairs: those that may be given as flags, then secrets,
+/// which may not since flags are visible to other users
+macro_rules! variables {
+    ($callback:ident) => {
+        $callback! {
+            flags {
+                database_uri: "DATABASE_URI",
+                tf_http_username: "TF_HTTP_USERNAME",
+                http_port: "HTTP_PORT",
+                http_bind_address: "HTTP_BIND_ADDRESS",
+                shutdown_timeout: "SHUTDOWN_TIMEOUT",
+                log_level: "LOG_LEVEL",
+                auth_file: "AUTH_FILE",
+                backup_dir: "BACKUP_DIR",
+                backup_interval: "BACKUP_INTERVAL",
+                backup_retain: "BACKUP_RETAIN",
+                webhooks_file: "WEBHOOKS_FILE",
+                webhook_max_attempts: "WEBHOOK_MAX_ATTEMPTS",
+                stale_lock_age: "STALE_LOCK_AGE",
+                duplicate_resources: "DUPLICATE_RESOURCES",
+                rules_file: "RULES_FILE",
+                rules_mode: "RULES_MODE",
+                redact_secrets: "REDACT_SECRETS",
+                encryption_keys_file: "ENCRYPTION_KEYS_FILE",
+                state_compression: "STATE_COMPRESSION",
+                state_history: "STATE_HISTORY",
+                state_history_snapshot_interval: "STATE_HISTORY_SNAPSHOT_INTERVAL",
+                state_history_retention: "STATE_HISTORY_RETENTION",
+                storage_backend: "STORAGE_BACKEND",
+                s3_endpoint: "S3_ENDPOINT",
+                s3_bucket: "S3_BUCKET",
+                s3_region: "S3_REGION",
+                s3_access_key_id: "S3_ACCESS_KEY_ID",
+                s3_key_prefix: "S3_KEY_PREFIX",
+            }
+            secrets {
+                tf_http_password: "TF_HTTP_PASSWORD",
+                encryption_keys: "ENCRYPTION_KEYS",
+                s3_secret_access_key: "S3_SECRET_ACCESS_KEY",
+            }
+        }
+    };
+}
+
+pub(crate) use variables;
+
+
+macro_rules! variable_names {
+    (
+        flags { $($flag:ident: $flag_name:literal,)* }
+        secrets { $($secret:ident: $secret_name:literal,)* }
+    ) => {
+        /// Every variable read by `Configuration`
+        pub const VARIABLES: &[&str] = &[$($flag_name,)* $($secret_name,)*];
+
+        /// Variables that have no flag
+        pub const SECRETS: &[&str] = &[$($secret_name,)*];
+
+        /// Destructures `config` without `..`, so this only compiles while
+        /// the table names exactly the fields of `Configuration`, and checks
+        /// each field is named after its variable
+        #[cfg(test)]
+        fn check_variables(config: Configuration) {
+            let Configuration { $($flag: _,)* $($secret: _,)* } = config;
+
+            $(assert_eq!(stringify!($flag).to_uppercase(), $flag_name);)*
+            $(assert_eq!(stringify!($secret).to_uppercase(), $secret_name);)*
+        }
+    };
+}
+
+variables!(variable_names);
+
+
+/// A configuration and the auth policy, webhooks and rules loaded from it
+#[derive(Debug)]
+pub struct Settings {
+    pub config: Configuration,
+    pub policy: Policy,
+    pub webhooks: Vec<Webhook>,
+    pub rules: Vec<Rule>,
+}
 
 
+/// The current `Settings`, which a reload replaces as a whole
+#[derive(Debug)]
+pub struct ConfigurationHandle {
+    current: RwLock<Arc<Settings>>,
+}
+
+
+#[derive(Debug)]
+pub enum ConfigError {
+    File(String),
+    Invalid(String),
+}
+
 
 #[derive(Debug, Envconfig)]
 pub struct Configuration {
@@ -27,6 +151,413 @@ pub struct Configuration {
     #[envconfig(from = "HTTP_BIND_ADDRESS", default = "0.0.0.0")]
     pub http_bind_address: IpAddr,
 
+    #[envconfig(from = "SHUTDOWN_TIMEOUT", default = "30")]
+    pub shutdown_timeout: u64,
+
     #[envconfig(from = "LOG_LEVEL", default = "INFO")]
     pub log_level: EnvFilter,
+
+    #[envconfig(from = "AUTH_FILE")]
+    pub auth_file: Option<String>,
+
+    #[envconfig(from = "BACKUP_DIR")]
+    pub backup_dir: Option<String>,
+
+    #[envconfig(from = "BACKUP_INTERVAL", default = "0")]
+    pub backup_interval: u64,
+
+    #[envconfig(from = "BACKUP_RETAIN", default = "7")]
+    pub backup_retain: usize,
+
+    #[envconfig(from = "WEBHOOKS_FILE")]
+    pub webhooks_file: Option<String>,
+
+    #[envconfig(from = "WEBHOOK_MAX_ATTEMPTS", default = "10")]
+    pub webhook_max_attempts: i64,
+
+    #[envconfig(from = "STALE_LOCK_AGE", default = "3600")]
+    pub stale_lock_age: u64,
+
+    #[envconfig(from = "DUPLICATE_RESOURCES", default = "warn")]
+    pub duplicate_resources: DuplicatePolicy,
+
+    #[envconfig(from = "RULES_FILE")]
+    pub rules_file: Option<String>,
+
+    #[envconfig(from = "RULES_MODE", default = "enforce")]
+    pub rules_mode: RulesMode,
+
+    #[envconfig(from = "REDACT_SECRETS", default = "false")]
+    pub redact_secrets: bool,
+
+    #[envconfig(from = "ENCRYPTION_KEYS")]
+    pub encryption_keys: Option<String>,
+
+    #[envconfig(from = "ENCRYPTION_KEYS_FILE")]
+    pub encryption_keys_file: Option<String>,
+
+    #[envconfig(from = "STATE_COMPRESSION", default = "zstd")]
+    pub state_compression: StateCodec,
+
+    #[envconfig(from = "STATE_HISTORY", default = "none")]
+    pub state_history: HistoryMode,
+
+    #[envconfig(from = "STATE_HISTORY_SNAPSHOT_INTERVAL", default = "20")]
+    pub state_history_snapshot_interval: i64,
+
+    #[envconfig(from = "STATE_HISTORY_RETENTION", default = "100")]
+    pub state_history_retention: i64,
+
+    #[envconfig(from = "STORAGE_BACKEND", default = "sqlite")]
+    pub storage_backend: StorageBackend,
+
+    #[envconfig(from = "S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    #[envconfig(from = "S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    #[envconfig(from = "S3_REGION", default = "us-east-1")]
+    pub s3_region: String,
+
+    #[envconfig(from = "S3_ACCESS_KEY_ID")]
+    pub s3_access_key_id: Option<String>,
+
+    #[envconfig(from = "S3_SECRET_ACCESS_KEY")]
+    pub s3_secret_access_key: Option<String>,
+
+    #[envconfig(from = "S3_KEY_PREFIX", default = "terraform")]
+    pub s3_key_prefix: String,
+}
+
+
+impl Configuration {
+    /// Loads configuration from flags, then the environment, then the config
+    /// file, falling back to defaults for anything none of them set
+    pub fn load(file: Option<&Path>, flags: HashMap<String, String>) -> Result<Self, ConfigError> {
+        Self::from_sources(file, env::vars().collect(), flags)
+    }
+
+    pub fn from_sources(
+        file: Option<&Path>,
+        env: HashMap<String, String>,
+        flags: HashMap<String, String>,
+    ) -> Result<Self, ConfigError> {
+        let mut values = match file {
+            Some(path) => resolve_secret_files(read_config_file(path)?)?,
+            None => HashMap::new(),
+        };
+
+        let env = env
+            .into_iter()
+            .filter(|(key, _)| is_variable(key))
+            .collect();
+
+        values.extend(resolve_secret_files(env)?);
+        values.extend(flags);
+
+        Self::init_from_hashmap(&values)
+            .map_err(|e| ConfigError::Invalid(e.to_string()))
+    }
+
+    /// Variables that differ in `new` but are only read at startup, so a
+    /// reload to `new` leaves them at their current values
+    pub fn restart_required(&self, new: &Self) -> Vec<&'static str> {
+        let changed = [
+            ("DATABASE_URI", self.database_uri != new.database_uri),
+            ("HTTP_PORT", self.http_port != new.http_port),
+            ("HTTP_BIND_ADDRESS", self.http_bind_address != new.http_bind_address),
+            ("SHUTDOWN_TIMEOUT", self.shutdown_timeout != new.shutdown_timeout),
+            ("BACKUP_INTERVAL", self.backup_interval != new.backup_interval),
+            ("STALE_LOCK_AGE", self.stale_lock_age != new.stale_lock_age),
+            ("ENCRYPTION_KEYS", self.encryption_keys != new.encryption_keys),
+            ("ENCRYPTION_KEYS_FILE", self.encryption_keys_file != new.encryption_keys_file),
+            ("STATE_COMPRESSION", self.state_compression != new.state_compression),
+            ("STATE_HISTORY", self.state_history != new.state_history),
+            ("STATE_HISTORY_SNAPSHOT_INTERVAL", self.state_history_snapshot_interval != new.state_history_snapshot_interval),
+            ("STATE_HISTORY_RETENTION", self.state_history_retention != new.state_history_retention),
+            ("STORAGE_BACKEND", self.storage_backend != new.storage_backend),
+            ("S3_ENDPOINT", self.s3_endpoint != new.s3_endpoint),
+            ("S3_BUCKET", self.s3_bucket != new.s3_bucket),
+            ("S3_REGION", self.s3_region != new.s3_region),
+            ("S3_ACCESS_KEY_ID", self.s3_access_key_id != new.s3_access_key_id),
+            ("S3_SECRET_ACCESS_KEY", self.s3_secret_access_key != new.s3_secret_access_key),
+            ("S3_KEY_PREFIX", self.s3_key_prefix != new.s3_key_prefix),
+        ];
+
+        changed
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(variable, _)| *variable)
+            .collect()
+    }
+}
+
+
+impl Settings {
+    pub fn new(config: Configuration) -> Result<Self, ConfigError> {
+        let policy = Policy::from_config(&config)?;
+        let webhooks = Webhook::from_config(&config)?;
+        let rules = Rule::from_config(&config)?;
+
+        Ok(Self {
+            config,
+            policy,
+            webhooks,
+            rules,
+        })
+    }
+}
+
+
+impl ConfigurationHandle {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(settings)),
+        }
+    }
+
+    /// Settings as of now; hold on to the returned value rather than calling
+    /// this repeatedly so one request sees one configuration
+    pub fn current(&self) -> Arc<Settings> {
+        self.current
+            .read()
+            .expect("Configuration lock poisoned")
+            .clone()
+    }
+
+    pub fn replace(&self, settings: Settings) {
+        *self.current
+            .write()
+            .expect("Configuration lock poisoned") = Arc::new(settings);
+    }
+}
+
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(e) => write!(f, "Config file error: {}", e),
+            Self::Invalid(e) => write!(f, "Invalid configuration: {}", e),
+        }
+    }
+}
+
+
+impl std::error::Error for ConfigError {}
+
+
+/// Files named by `*_FILE` variables in the environment or the config file at
+/// `file`, whose contents are read in place of the variables they supply
+pub fn secret_files(file: Option<&Path>) -> Vec<PathBuf> {
+    let values = file
+        .and_then(|path| read_config_file(path).ok())
+        .unwrap_or_default();
+
+    env::vars()
+        .chain(values)
+        .filter(|(key, _)| secret_file_target(key).is_some())
+        .map(|(_, value)| PathBuf::from(value))
+        .collect()
+}
+
+
+/// Whether `key` is a configuration variable or names a file holding one
+fn is_variable(key: &str) -> bool {
+    VARIABLES.contains(&key) || secret_file_target(key).is_some()
+}
+
+
+/// The variable `key` supplies under the `*_FILE` convention, unless `key` is
+/// itself a variable such as `ENCRYPTION_KEYS_FILE`
+fn secret_file_target(key: &str) -> Option<&'static str> {
+    if VARIABLES.contains(&key) {
+        return None;
+    }
+
+    let target = key.strip_suffix("_FILE")?;
+
+    VARIABLES
+        .iter()
+        .find(|variable| **variable == target)
+        .copied()
+}
+
+
+/// Replaces each `*_FILE` entry with the contents of the file it names
+fn resolve_secret_files(values: HashMap<String, String>) -> Result<HashMap<String, String>, ConfigError> {
+    let mut resolved = HashMap::new();
+
+    for (key, value) in values.iter() {
+        let target = match secret_file_target(key) {
+            Some(target) => target,
+            None => {
+                resolved.insert(key.clone(), value.clone());
+                continue;
+            },
+        };
+
+        if values.contains_key(target) {
+            return Err(ConfigError::Invalid(format!("Only one of {} and {} may be set", target, key)));
+        }
+
+        let contents = fs::read_to_string(value)
+            .map_err(|e| ConfigError::File(format!("Failed to read {} from {}: {}", target, value, e)))?;
+
+        resolved.insert(target.to_string(), contents.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+
+    Ok(resolved)
+}
+
+
+/// Parses a TOML file, or YAML if the extension is `.yaml` or `.yml`
+pub fn parse_file<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| ConfigError::File(format!("Failed to read {}: {}", path.display(), e)))?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+
+    match extension {
+        "yaml" | "yml" => serde_yaml::from_str(&contents)
+            .map_err(|e| ConfigError::File(format!("Malformed YAML in {}: {}", path.display(), e))),
+        _ => toml::from_str(&contents)
+            .map_err(|e| ConfigError::File(format!("Malformed TOML in {}: {}", path.display(), e))),
+    }
+}
+
+
+/// Reads a config file whose keys are variable names in either case
+fn read_config_file(path: &Path) -> Result<HashMap<String, String>, ConfigError> {
+    let table: HashMap<String, Value> = parse_file(path)?;
+    let mut values = HashMap::new();
+
+    for (key, value) in table {
+        let key = key.to_uppercase();
+
+        if !is_variable(&key) {
+            return Err(ConfigError::File(format!("Unknown setting {} in {}", key, path.display())));
+        }
+
+        values.insert(key.clone(), config_value(&key, value)?);
+    }
+
+    Ok(values)
+}
+
+
+/// Scalars become their string form; lists such as encryption keys are joined
+/// with commas
+fn config_value(key: &str, value: Value) -> Result<String, ConfigError> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| config_value(key, value))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|values| values.join(",")),
+        _ => Err(ConfigError::File(format!("{} must be a string, number, boolean or list", key))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs,
+        path::PathBuf,
+    };
+
+    use super::{
+        ConfigError,
+        Configuration,
+        check_variables,
+        secret_files,
+    };
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("tf-http-backend-{}-{}", std::process::id(), name));
+
+        fs::write(&path, contents)
+            .unwrap();
+
+        path
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let file = temp_file("precedence.toml", "tf_http_username = \"file\"\ntf_http_password = \"file\"\nhttp_port = 9000\ns3_region = \"eu-west-1\"\n");
+        let env = pairs(&[("TF_HTTP_PASSWORD", "env"), ("HTTP_PORT", "9001"), ("HOME", "/root")]);
+        let flags = pairs(&[("HTTP_PORT", "9002")]);
+
+        let config = Configuration::from_sources(Some(&file), env, flags)
+            .unwrap();
+
+        assert_eq!(config.tf_http_username, "file");
+        assert_eq!(config.tf_http_password, "env");
+        assert_eq!(config.http_port, 9002);
+        assert_eq!(config.s3_region, "eu-west-1");
+        assert_eq!(config.shutdown_timeout, 30);
+    }
+
+    #[test]
+    fn test_variables() {
+        let env = pairs(&[("TF_HTTP_USERNAME", "admin"), ("TF_HTTP_PASSWORD", "a")]);
+
+        check_variables(Configuration::from_sources(None, env, HashMap::new()).unwrap());
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let env = pairs(&[("TF_HTTP_USERNAME", "admin"), ("TF_HTTP_PASSWORD", "a")]);
+        let current = Configuration::from_sources(None, env.clone(), HashMap::new())
+            .unwrap();
+        let reloaded = Configuration::from_sources(None, env, pairs(&[("TF_HTTP_PASSWORD", "b"), ("REDACT_SECRETS", "true"), ("HTTP_PORT", "9000"), ("S3_BUCKET", "states")]))
+            .unwrap();
+
+        assert!(current.restart_required(&current).is_empty());
+        assert_eq!(current.restart_required(&reloaded), vec!["HTTP_PORT", "S3_BUCKET"]);
+    }
+
+    #[test]
+    fn test_yaml_and_secret_files() {
+        let password = temp_file("password", "hunter2\n");
+        let file = temp_file("secrets.yaml", &format!("TF_HTTP_USERNAME: admin\nTF_HTTP_PASSWORD_FILE: {}\nencryption_keys: [\"a:x\", \"b:y\"]\n", password.display()));
+
+        let config = Configuration::from_sources(Some(&file), HashMap::new(), HashMap::new())
+            .unwrap();
+
+        assert_eq!(config.tf_http_password, "hunter2");
+        assert_eq!(config.encryption_keys.as_deref(), Some("a:x,b:y"));
+        assert!(secret_files(Some(&file)).contains(&password));
+
+        // a secret file and a plain value for the same variable are ambiguous
+        let env = pairs(&[("TF_HTTP_USERNAME", "admin"), ("TF_HTTP_PASSWORD", "a"), ("TF_HTTP_PASSWORD_FILE", password.to_str().unwrap())]);
+
+        assert!(matches!(Configuration::from_sources(None, env, HashMap::new()), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let file = temp_file("unknown.toml", "tf_http_usernme = \"typo\"\n");
+
+        assert!(matches!(Configuration::from_sources(Some(&file), HashMap::new(), HashMap::new()), Err(ConfigError::File(_))));
+
+        let env = pairs(&[("TF_HTTP_USERNAME", "admin"), ("TF_HTTP_PASSWORD", "a"), ("HTTP_PORT", "not-a-port")]);
+
+        assert!(matches!(Configuration::from_sources(None, env, HashMap::new()), Err(ConfigError::Invalid(_))));
+    }
 }
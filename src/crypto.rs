@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+};
+
+use aes_gcm::{
+    Aes256Gcm,
+    Key,
+    KeyInit,
+    Nonce,
+    aead::{
+        Aead,
+        AeadCore,
+        OsRng,
+        Payload,
+    },
+};
+
+use crate::config::Configuration;
+
+
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CryptoError(pub String);
+
+
+/// A state document encrypted under a data key, which is in turn wrapped by
+/// the master key identified by `key_id`
+#[derive(Debug, Clone)]
+pub struct EncryptedState {
+    pub key_id: String,
+    pub data_key: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+
+/// Master keys by id; new data keys are always wrapped by the primary key,
+/// the remaining keys are kept so older rows can still be read
+pub struct Keyring {
+    primary: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+
+impl std::error::Error for CryptoError {}
+
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<&String> = self.keys.keys().collect();
+        ids.sort();
+
+        f.debug_struct("Keyring")
+            .field("primary", &self.primary)
+            .field("keys", &ids)
+            .finish()
+    }
+}
+
+
+impl Keyring {
+    /// Parses `key_id:base64_key` entries separated by commas or newlines;
+    /// the first entry is the primary key
+    pub fn parse<S: AsRef<str>>(entries: S) -> Result<Self, CryptoError> {
+        let mut primary = None;
+        let mut keys = HashMap::new();
+
+        let entries = entries
+            .as_ref()
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty() && !entry.starts_with('#'));
+
+        for entry in entries {
+            let (key_id, encoded) = entry
+                .split_once(':')
+                .ok_or_else(|| CryptoError("Malformed encryption key entry; expected key_id:base64_key".to_string()))?;
+
+            let key = base64::decode(encoded.trim())
+                .map_err(|e| CryptoError(format!("Encryption key {} is not valid base64: {}", key_id, e)))?;
+
+            if key.len() != KEY_LEN {
+                return Err(CryptoError(format!("Encryption key {} must be {} bytes", key_id, KEY_LEN)));
+            }
+
+            let key_id = key_id.trim().to_string();
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+
+            if keys.insert(key_id.clone(), cipher).is_some() {
+                return Err(CryptoError(format!("Duplicate encryption key id {}", key_id)));
+            }
+
+            primary.get_or_insert(key_id);
+        }
+
+        let primary = primary.ok_or_else(|| CryptoError("No encryption keys defined".to_string()))?;
+
+        Ok(Self {
+            primary,
+            keys,
+        })
+    }
+
+    /// Loads the keyring from `ENCRYPTION_KEYS` or `ENCRYPTION_KEYS_FILE`,
+    /// returning None if encryption is not configured
+    pub fn from_config(config: &Configuration) -> Result<Option<Self>, CryptoError> {
+        let entries = match (&config.encryption_keys, &config.encryption_keys_file) {
+            (Some(_), Some(_)) => return Err(CryptoError("Only one of ENCRYPTION_KEYS and ENCRYPTION_KEYS_FILE may be set".to_string())),
+            (Some(entries), None) => entries.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|e| CryptoError(format!("Failed to read {}: {}", path, e)))?,
+            (None, None) => return Ok(None),
+        };
+
+        Self::parse(entries).map(Some)
+    }
+
+    pub fn primary_key_id(&self) -> &str {
+        &self.primary
+    }
+
+    /// Encrypts a state under a fresh data key; `id` is bound as associated
+    /// data so ciphertext cannot be moved between resources
+    pub fn encrypt(&self, id: &str, plaintext: &[u8]) -> Result<EncryptedState, CryptoError> {
+        let data_key = Aes256Gcm::generate_key(&mut OsRng);
+        let ciphertext = seal(&Aes256Gcm::new(&data_key), id, plaintext)?;
+        let data_key = seal(self.cipher(&self.primary)?, id, &data_key)?;
+
+        Ok(EncryptedState {
+            key_id: self.primary.clone(),
+            data_key,
+            ciphertext,
+        })
+    }
+
+    pub fn decrypt(&self, id: &str, state: &EncryptedState) -> Result<Vec<u8>, CryptoError> {
+        let data_key = self.unwrap_data_key(id, &state.key_id, &state.data_key)?;
+
+        open(&Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key)), id, &state.ciphertext)
+    }
+
+    /// Re-wraps the data key under the primary key, leaving the ciphertext
+    /// untouched
+    pub fn rewrap(&self, id: &str, state: &EncryptedState) -> Result<EncryptedState, CryptoError> {
+        let data_key = self.unwrap_data_key(id, &state.key_id, &state.data_key)?;
+
+        Ok(EncryptedState {
+            key_id: self.primary.clone(),
+            data_key: seal(self.cipher(&self.primary)?, id, &data_key)?,
+            ciphertext: state.ciphertext.clone(),
+        })
+    }
+
+    fn unwrap_data_key(&self, id: &str, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let data_key = open(self.cipher(key_id)?, id, wrapped)?;
+
+        if data_key.len() != KEY_LEN {
+            return Err(CryptoError("Unwrapped data key has an invalid length".to_string()));
+        }
+
+        Ok(data_key)
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<&Aes256Gcm, CryptoError> {
+        self.keys
+            .get(key_id)
+            .ok_or_else(|| CryptoError(format!("Unknown encryption key id {}", key_id)))
+    }
+}
+
+
+/// Encrypts with a random nonce, returning nonce || ciphertext
+fn seal(cipher: &Aes256Gcm, aad: &str, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: plaintext,
+        aad: aad.as_bytes(),
+    };
+
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| CryptoError("Encryption failed".to_string()))?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+
+fn open(cipher: &Aes256Gcm, aad: &str, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError("Ciphertext is truncated".to_string()));
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: aad.as_bytes(),
+    };
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| CryptoError("Decryption failed; wrong key or corrupted data".to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Keyring;
+
+    const OLD_KEY: &str = "old:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const NEW_KEY: &str = "new:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let keyring = Keyring::parse(OLD_KEY)
+            .unwrap();
+
+        let encrypted = keyring.encrypt("105", b"state")
+            .unwrap();
+
+        assert_eq!(encrypted.key_id, "old");
+        assert_ne!(encrypted.ciphertext, b"state");
+        assert_eq!(keyring.decrypt("105", &encrypted).unwrap(), b"state");
+
+        // ciphertext is bound to the resource it was written for
+        assert!(keyring.decrypt("106", &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_rewrap() {
+        let old = Keyring::parse(OLD_KEY)
+            .unwrap();
+        let rotated = Keyring::parse(format!("{}\n{}", NEW_KEY, OLD_KEY))
+            .unwrap();
+        let new = Keyring::parse(NEW_KEY)
+            .unwrap();
+
+        let encrypted = old.encrypt("105", b"state")
+            .unwrap();
+        let rewrapped = rotated.rewrap("105", &encrypted)
+            .unwrap();
+
+        assert_eq!(rewrapped.key_id, "new");
+        assert_eq!(rewrapped.ciphertext, encrypted.ciphertext);
+        assert_eq!(new.decrypt("105", &rewrapped).unwrap(), b"state");
+        assert!(new.decrypt("105", &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("nokey").is_err());
+        assert!(Keyring::parse("short:AAAA").is_err());
+        assert!(Keyring::parse(format!("{},{}", OLD_KEY, OLD_KEY)).is_err());
+    }
+}
@@ -1,4 +1,10 @@
-use std::str::FromStr;
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+};
 
 use log::LevelFilter;
 use sqlx::{
@@ -24,3 +30,56 @@ pub async fn get_db_pool(config: &Configuration) -> Result<SqlitePool, Error> {
     SqlitePool::connect_with(opts)
         .await
 }
+
+
+/// The database file named by `DATABASE_URI`, or None for in-memory databases
+pub fn database_path(config: &Configuration) -> Option<PathBuf> {
+    let uri = config.database_uri
+        .trim_start_matches("sqlite://")
+        .trim_start_matches("sqlite:");
+    let path = uri
+        .split('?')
+        .next()
+        .unwrap_or_default();
+
+    if path.is_empty() || path == ":memory:" || config.database_uri == "memory://" {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+
+/// Returns the versions of migrations in `MIGRATE` not yet applied to the pool
+pub async fn pending_migrations(pool: &SqlitePool) -> Result<Vec<i64>, Error> {
+    let (tracked,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'")
+        .fetch_one(pool)
+        .await?;
+
+    let applied: Vec<(i64,)> = if tracked > 0 {
+        sqlx::query_as("SELECT version FROM _sqlx_migrations WHERE success = 1")
+            .fetch_all(pool)
+            .await?
+    } else {
+        Vec::new()
+    };
+
+    let pending = MIGRATE
+        .iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.iter().any(|(applied,)| applied == version))
+        .collect();
+
+    Ok(pending)
+}
+
+
+/// Writes a consistent copy of the database to `path`, which must not exist,
+/// while other connections keep reading and writing
+pub async fn backup(pool: &SqlitePool, path: &Path) -> Result<(), Error> {
+    sqlx::query("VACUUM INTO ?1")
+        .bind(path.to_string_lossy().as_ref())
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
@@ -1 +1,3 @@
+pub mod object;
+pub mod outbox;
 pub mod terraform;
@@ -0,0 +1,120 @@
+use chrono::NaiveDateTime;
+use sqlx::{
+    Error as SqlxError,
+    SqlitePool,
+};
+
+use crate::storage::StateSummary;
+
+
+/// Points a terraform resource at the object holding its current state
+#[derive(sqlx::FromRow, Debug)]
+pub struct StateObjectRow {
+    pub terraform_id: String,
+    pub version: i64,
+    pub object_key: String,
+    pub last_update_ts: NaiveDateTime,
+}
+
+
+/// A previously written object of a terraform resource
+#[derive(sqlx::FromRow, Debug)]
+pub struct StateObjectVersionRow {
+    pub terraform_id: String,
+    pub version: i64,
+    pub object_key: String,
+    pub created_ts: NaiveDateTime,
+}
+
+
+pub struct StateObjectQuery {
+    pool: SqlitePool,
+}
+
+
+impl StateObjectQuery {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool
+        }
+    }
+
+    pub async fn get<S: AsRef<str>>(&self, terraform_id: S) -> Result<Option<StateObjectRow>, SqlxError> {
+        sqlx::query_as::<_, StateObjectRow>("SELECT * FROM state_objects WHERE terraform_id = ?1")
+            .bind(terraform_id.as_ref())
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Reserves the next version of the resource, so that concurrent writers
+    /// never write the same object. The reservation has no object until
+    /// `create_or_replace` records it.
+    pub async fn allocate_version<S: AsRef<str>>(&self, terraform_id: S) -> Result<i64, SqlxError> {
+        let query = "INSERT INTO state_object_versions (terraform_id, version, object_key) \
+            SELECT ?1, COALESCE(MAX(version), 0) + 1, '' FROM state_object_versions WHERE terraform_id = ?1 \
+            RETURNING version";
+
+        let (version,): (i64,) = sqlx::query_as(query)
+            .bind(terraform_id.as_ref())
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(version)
+    }
+
+    /// Records the object of an allocated version and points the resource at
+    /// it. Returns `None` without recording anything if a later version is
+    /// already current.
+    pub async fn create_or_replace<S: AsRef<str>>(&self, terraform_id: S, version: i64, object_key: S) -> Result<Option<StateObjectRow>, SqlxError> {
+        let query = "INSERT INTO state_objects (terraform_id, version, object_key) VALUES (?1, ?2, ?3) \
+            ON CONFLICT (terraform_id) DO UPDATE SET version=excluded.version, object_key=excluded.object_key \
+            WHERE excluded.version > state_objects.version \
+            RETURNING *";
+
+        let mut tx = self.pool
+            .begin()
+            .await?;
+
+        sqlx::query("UPDATE state_object_versions SET object_key = ?3 WHERE terraform_id = ?1 AND version = ?2")
+            .bind(terraform_id.as_ref())
+            .bind(version)
+            .bind(object_key.as_ref())
+            .execute(&mut tx)
+            .await?;
+
+        let row = sqlx::query_as::<_, StateObjectRow>(query)
+            .bind(terraform_id.as_ref())
+            .bind(version)
+            .bind(object_key.as_ref())
+            .fetch_optional(&mut tx)
+            .await?;
+
+        if row.is_some() {
+            tx.commit()
+                .await?;
+        }
+
+        Ok(row)
+    }
+
+    pub async fn list(&self) -> Result<Vec<StateSummary>, SqlxError> {
+        sqlx::query_as::<_, StateSummary>("SELECT terraform_id AS id, last_update_ts FROM state_objects ORDER BY terraform_id")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn list_versions<S: AsRef<str>>(&self, terraform_id: S) -> Result<Vec<StateObjectVersionRow>, SqlxError> {
+        sqlx::query_as::<_, StateObjectVersionRow>("SELECT * FROM state_object_versions WHERE terraform_id = ?1 AND object_key != '' ORDER BY version")
+            .bind(terraform_id.as_ref())
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn get_version<S: AsRef<str>>(&self, terraform_id: S, version: i64) -> Result<Option<StateObjectVersionRow>, SqlxError> {
+        sqlx::query_as::<_, StateObjectVersionRow>("SELECT * FROM state_object_versions WHERE terraform_id = ?1 AND version = ?2 AND object_key != ''")
+            .bind(terraform_id.as_ref())
+            .bind(version)
+            .fetch_optional(&self.pool)
+            .await
+    }
+}
@@ -0,0 +1,95 @@
+use chrono::NaiveDateTime;
+use sqlx::{
+    Error as SqlxError,
+    SqlitePool,
+};
+
+
+/// A webhook delivery waiting in the outbox
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct OutboxRow {
+    pub id: i64,
+    pub hook: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i64,
+    pub next_attempt_ts: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    pub created_ts: NaiveDateTime,
+}
+
+
+pub struct OutboxQuery {
+    pool: SqlitePool,
+}
+
+
+impl OutboxQuery {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool
+        }
+    }
+
+    pub async fn enqueue<S: AsRef<str>>(&self, hook: S, event: S, payload: S, now: NaiveDateTime) -> Result<OutboxRow, SqlxError> {
+        sqlx::query_as::<_, OutboxRow>("INSERT INTO webhook_outbox (hook, event, payload, next_attempt_ts) VALUES (?1, ?2, ?3, ?4) RETURNING *")
+            .bind(hook.as_ref())
+            .bind(event.as_ref())
+            .bind(payload.as_ref())
+            .bind(now)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// Deliveries due by `now`, oldest first
+    pub async fn due(&self, now: NaiveDateTime, limit: i64) -> Result<Vec<OutboxRow>, SqlxError> {
+        sqlx::query_as::<_, OutboxRow>("SELECT * FROM webhook_outbox WHERE next_attempt_ts <= ?1 ORDER BY id LIMIT ?2")
+            .bind(now)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn delete(&self, id: i64) -> Result<(), SqlxError> {
+        sqlx::query("DELETE FROM webhook_outbox WHERE id = ?1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Moves the next attempt of a delivery without counting an attempt
+    pub async fn postpone(&self, id: i64, next_attempt_ts: NaiveDateTime) -> Result<(), SqlxError> {
+        sqlx::query("UPDATE webhook_outbox SET next_attempt_ts = ?2 WHERE id = ?1")
+            .bind(id)
+            .bind(next_attempt_ts)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes deliveries that were given up on and queued before `before`,
+    /// returning how many were removed
+    pub async fn purge_given_up(&self, before: NaiveDateTime) -> Result<u64, SqlxError> {
+        let result = sqlx::query("DELETE FROM webhook_outbox WHERE next_attempt_ts IS NULL AND created_ts < ?1")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Records a failed attempt; a `next_attempt_ts` of `None` gives up
+    pub async fn fail<S: AsRef<str>>(&self, id: i64, error: S, next_attempt_ts: Option<NaiveDateTime>) -> Result<(), SqlxError> {
+        sqlx::query("UPDATE webhook_outbox SET attempts = attempts + 1, last_error = ?2, next_attempt_ts = ?3 WHERE id = ?1")
+            .bind(id)
+            .bind(error.as_ref())
+            .bind(next_attempt_ts)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -1,11 +1,35 @@
+use std::{
+    fmt,
+    sync::Arc,
+};
+
 use chrono::NaiveDateTime;
+use serde_json::Value;
 use sqlx::{
     Error as SqlxError,
     SqlitePool,
 };
 
+use crate::{
+    codec::StateCodec,
+    crypto::{
+        EncryptedState,
+        Keyring,
+    },
+    history::{
+        self,
+        HistoryMode,
+        VersionKind,
+    },
+    storage::{
+        StateSummary,
+        StateVersion,
+        StorageError,
+    },
+};
 
-#[derive(sqlx::FromRow, Debug)]
+
+#[derive(sqlx::FromRow, Debug, Clone)]
 pub struct TerraformRow {
     pub id: String,
     pub state: String,
@@ -13,7 +37,40 @@ pub struct TerraformRow {
 }
 
 
-#[derive(sqlx::FromRow, Debug)]
+/// A terraform row as stored; `state` holds the codec encoded body, which is
+/// encrypted when `key_id` is set. `version` is the history version the row
+/// corresponds to, if history was recorded when it was written.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct TerraformRecord {
+    pub id: String,
+    pub state: Vec<u8>,
+    pub codec: String,
+    pub key_id: Option<String>,
+    pub data_key: Option<Vec<u8>>,
+    pub version: Option<i64>,
+    pub aad_format: i64,
+    pub last_update_ts: NaiveDateTime,
+}
+
+
+/// A previous version of a terraform row; `state` is encoded the same way as
+/// `TerraformRecord` and holds either a full snapshot or a patch against the
+/// prior version
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct TerraformVersionRecord {
+    pub terraform_id: String,
+    pub version: i64,
+    pub kind: String,
+    pub state: Vec<u8>,
+    pub codec: String,
+    pub key_id: Option<String>,
+    pub data_key: Option<Vec<u8>>,
+    pub aad_format: i64,
+    pub created_ts: NaiveDateTime,
+}
+
+
+#[derive(sqlx::FromRow, Debug, Clone)]
 pub struct TerraformLockRow {
     pub id: String,
     pub terraform_id: String,
@@ -24,47 +81,598 @@ pub struct TerraformLockRow {
 
 pub struct TerraformQuery {
     pool: SqlitePool,
+    keyring: Option<Arc<Keyring>>,
+    codec: StateCodec,
+    history: HistoryMode,
+    snapshot_interval: i64,
+    retention: i64,
+}
+
+
+/// Codec name of encrypted rows written as base64 text before state bodies
+/// were stored as BLOBs; the ciphertext wraps an uncompressed body
+const LEGACY_BASE64_CODEC: &str = "base64";
+
+
+/// Format of the associated data bodies are written with. Format 1 joined a
+/// version to its resource id with `@`, so `a@1` named both version 1 of `a`
+/// and the current row of `a@1`.
+const AAD_FORMAT: i64 = 2;
+
+
+/// The resource, and version if any, an encrypted body is bound to
+#[derive(Clone, Copy)]
+enum Binding<'a> {
+    State(&'a str),
+    Version(&'a str, i64),
+}
+
+
+struct EncodedState {
+    state: Vec<u8>,
+    codec: StateCodec,
+    key_id: Option<String>,
+    data_key: Option<Vec<u8>>,
+}
+
+
+/// Borrowed encoded body columns shared by current rows and versions;
+/// `aad_format` is the format of the associated data the body was encrypted
+/// with
+struct StoredBody<'a> {
+    binding: Binding<'a>,
+    aad_format: i64,
+    state: &'a [u8],
+    codec: &'a str,
+    key_id: &'a Option<String>,
+    data_key: &'a Option<Vec<u8>>,
 }
 
 
 #[derive(Debug)]
 pub enum MaybeConflictError {
     Conflict(TerraformLockRow),
-    Error(SqlxError),
+    Error(StorageError),
 }
 
 
 impl From<SqlxError> for MaybeConflictError {
     fn from(e: SqlxError) -> Self {
+        Self::Error(e.into())
+    }
+}
+
+
+impl From<StorageError> for MaybeConflictError {
+    fn from(e: StorageError) -> Self {
         Self::Error(e)
     }
 }
 
 
+impl TerraformRecord {
+    fn body(&self) -> StoredBody<'_> {
+        StoredBody {
+            binding: Binding::State(&self.id),
+            aad_format: self.aad_format,
+            state: &self.state,
+            codec: &self.codec,
+            key_id: &self.key_id,
+            data_key: &self.data_key,
+        }
+    }
+}
+
+
+impl TerraformVersionRecord {
+    fn body(&self) -> StoredBody<'_> {
+        StoredBody {
+            binding: Binding::Version(&self.terraform_id, self.version),
+            aad_format: self.aad_format,
+            state: &self.state,
+            codec: &self.codec,
+            key_id: &self.key_id,
+            data_key: &self.data_key,
+        }
+    }
+}
+
+
+impl Binding<'_> {
+    /// Associated data in the given format; the id is length prefixed and
+    /// each kind of binding tagged, so no two bindings share it
+    fn aad(&self, format: i64) -> String {
+        match (self, format) {
+            (Self::State(id), 1) => id.to_string(),
+            (Self::Version(id, version), 1) => format!("{}@{}", id, version),
+            (Self::State(id), _) => format!("state:{}:{}", id.len(), id),
+            (Self::Version(id, version), _) => format!("version:{}:{}:{}", id.len(), id, version),
+        }
+    }
+}
+
+
+impl fmt::Display for Binding<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::State(id) => write!(f, "{}", id),
+            Self::Version(id, version) => write!(f, "{} version {}", id, version),
+        }
+    }
+}
+
+
+impl StoredBody<'_> {
+    fn aad(&self) -> String {
+        self.binding.aad(self.aad_format)
+    }
+
+    /// Codec of the plaintext body, once any encryption has been removed
+    fn codec(&self) -> Result<StateCodec, StorageError> {
+        if self.codec == LEGACY_BASE64_CODEC {
+            return Ok(StateCodec::Identity);
+        }
+
+        self.codec
+            .parse()
+            .map_err(StorageError::Codec)
+    }
+
+    fn encrypted(&self) -> Result<Option<EncryptedState>, StorageError> {
+        let (key_id, data_key) = match (self.key_id, self.data_key) {
+            (Some(key_id), Some(data_key)) => (key_id, data_key),
+            _ => return Ok(None),
+        };
+
+        let ciphertext = if self.codec == LEGACY_BASE64_CODEC {
+            base64::decode(self.state)
+                .map_err(|e| StorageError::Encryption(format!("Malformed ciphertext for {}: {}", self.binding, e)))?
+        } else {
+            self.state.to_vec()
+        };
+
+        Ok(Some(EncryptedState {
+            key_id: key_id.clone(),
+            data_key: data_key.clone(),
+            ciphertext,
+        }))
+    }
+}
+
+
 impl TerraformQuery {
     pub fn new(pool: SqlitePool) -> Self {
         Self {
-            pool
+            pool,
+            keyring: None,
+            codec: StateCodec::Identity,
+            history: HistoryMode::None,
+            snapshot_interval: 1,
+            retention: 0,
+        }
+    }
+
+    /// Encrypts states written through this query under the keyring's
+    /// primary key; reading encrypted rows requires a keyring
+    pub fn with_keyring(self, keyring: Option<Arc<Keyring>>) -> Self {
+        Self {
+            keyring,
+            ..self
+        }
+    }
+
+    /// Encodes states written through this query with the given codec; rows
+    /// are always decoded with the codec they were written with
+    pub fn with_codec(self, codec: StateCodec) -> Self {
+        Self {
+            codec,
+            ..self
+        }
+    }
+
+    /// Records a version for every state written through this query. In
+    /// delta mode a full snapshot is kept every `snapshot_interval` versions.
+    pub fn with_history(self, history: HistoryMode, snapshot_interval: i64) -> Self {
+        Self {
+            history,
+            snapshot_interval: snapshot_interval.max(1),
+            ..self
+        }
+    }
+
+    /// Prunes the history of a state on every write to its latest `retention`
+    /// versions, or 0 to keep them all. Deltas are only removed along with
+    /// the snapshot they depend on, so up to `snapshot_interval - 1` more
+    /// versions may be kept in delta mode.
+    pub fn with_retention(self, retention: i64) -> Self {
+        Self {
+            retention: retention.max(0),
+            ..self
         }
     }
 
     /// Returns an optional terraform row for a given terraform id
-    pub async fn get<S: AsRef<str>>(&self, id: S) -> Result<Option<TerraformRow>, SqlxError> {
-        sqlx::query_as::<_, TerraformRow>("SELECT * FROM terraform WHERE id = ?1")
+    pub async fn get<S: AsRef<str>>(&self, id: S) -> Result<Option<TerraformRow>, StorageError> {
+        let record = sqlx::query_as::<_, TerraformRecord>("SELECT * FROM terraform WHERE id = ?1")
             .bind(id.as_ref())
             .fetch_optional(&self.pool)
-            .await
+            .await?;
+
+        record
+            .map(|record| self.decode_record(record))
+            .transpose()
     }
 
-    pub async fn create_or_replace<S: AsRef<str>>(&self, id: S, state: S) -> Result<TerraformRow, SqlxError> {
-        let query = "INSERT INTO terraform (id, state) VALUES (?1, ?2) \
-            ON CONFLICT (id) DO UPDATE SET state=excluded.state \
+    pub async fn create_or_replace<S: AsRef<str>>(&self, id: S, state: S) -> Result<TerraformRow, StorageError> {
+        let query = "INSERT INTO terraform (id, state, codec, key_id, data_key, version, aad_format) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+            ON CONFLICT (id) DO UPDATE SET state=excluded.state, codec=excluded.codec, key_id=excluded.key_id, \
+            data_key=excluded.data_key, version=excluded.version, aad_format=excluded.aad_format \
             RETURNING *";
 
-        sqlx::query_as::<_, TerraformRow>(query)
+        let (id, state) = (id.as_ref(), state.as_ref());
+        let mut tx = self.pool
+            .begin()
+            .await?;
+
+        let version = match self.history {
+            HistoryMode::None => None,
+            _ => {
+                let (latest,): (Option<i64>,) = sqlx::query_as("SELECT MAX(version) FROM terraform_versions WHERE terraform_id = ?1")
+                    .bind(id)
+                    .fetch_one(&mut tx)
+                    .await?;
+
+                Some(latest.unwrap_or(0) + 1)
+            },
+        };
+
+        // a delta is only recorded against the current row when it is known
+        // to be the latest version
+        let delta = match version {
+            Some(version) if self.history == HistoryMode::Delta && (version - 1) % self.snapshot_interval != 0 => {
+                let previous = sqlx::query_as::<_, TerraformRecord>("SELECT * FROM terraform WHERE id = ?1")
+                    .bind(id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .filter(|record| record.version == Some(version - 1));
+
+                match previous {
+                    Some(previous) => history::diff(&self.decode_record(previous)?.state, state),
+                    None => None,
+                }
+            },
+            _ => None,
+        };
+
+        let encoded = self.encode(Binding::State(id), state)?;
+        let record = sqlx::query_as::<_, TerraformRecord>(query)
+            .bind(id)
+            .bind(encoded.state)
+            .bind(encoded.codec.as_str())
+            .bind(encoded.key_id)
+            .bind(encoded.data_key)
+            .bind(version)
+            .bind(AAD_FORMAT)
+            .fetch_one(&mut tx)
+            .await?;
+
+        if let Some(version) = version {
+            let (kind, body) = match &delta {
+                Some(patch) => (VersionKind::Delta, patch.as_str()),
+                None => (VersionKind::Snapshot, state),
+            };
+
+            let encoded = self.encode(Binding::Version(id, version), body)?;
+
+            sqlx::query("INSERT INTO terraform_versions (terraform_id, version, kind, state, codec, key_id, data_key, aad_format) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")
+                .bind(id)
+                .bind(version)
+                .bind(kind.as_str())
+                .bind(encoded.state)
+                .bind(encoded.codec.as_str())
+                .bind(encoded.key_id)
+                .bind(encoded.data_key)
+                .bind(AAD_FORMAT)
+                .execute(&mut tx)
+                .await?;
+
+            if self.retention > 0 {
+                // keep the snapshot the oldest retained version is built from
+                let prune = "DELETE FROM terraform_versions WHERE terraform_id = ?1 AND version < \
+                    (SELECT MAX(version) FROM terraform_versions WHERE terraform_id = ?1 AND version <= ?2 AND kind = 'snapshot')";
+
+                sqlx::query(prune)
+                    .bind(id)
+                    .bind(version - self.retention + 1)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        tx.commit()
+            .await?;
+
+        Ok(TerraformRow {
+            id: record.id,
+            state: state.to_string(),
+            last_update_ts: record.last_update_ts,
+        })
+    }
+
+    /// Lists the recorded versions of a terraform resource, oldest first
+    pub async fn list_versions<S: AsRef<str>>(&self, id: S) -> Result<Vec<StateVersion>, StorageError> {
+        let versions = sqlx::query_as::<_, StateVersion>("SELECT version, created_ts AS last_update_ts FROM terraform_versions WHERE terraform_id = ?1 ORDER BY version")
             .bind(id.as_ref())
-            .bind(state.as_ref())
-            .fetch_one(&self.pool)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(versions)
+    }
+
+    /// Reconstructs a version from the closest snapshot at or before it and
+    /// the deltas which follow
+    pub async fn get_version<S: AsRef<str>>(&self, id: S, version: i64) -> Result<Option<TerraformRow>, StorageError> {
+        let query = "SELECT * FROM terraform_versions WHERE terraform_id = ?1 AND version <= ?2 AND version >= \
+            (SELECT MAX(version) FROM terraform_versions WHERE terraform_id = ?1 AND version <= ?2 AND kind = 'snapshot') \
+            ORDER BY version";
+
+        let records = sqlx::query_as::<_, TerraformVersionRecord>(query)
+            .bind(id.as_ref())
+            .bind(version)
+            .fetch_all(&self.pool)
+            .await?;
+
+        match records.last() {
+            Some(last) if last.version == version => (),
+            _ => return Ok(None),
+        };
+
+        // deltas are applied to a single parsed document which is only
+        // serialized once the requested version is reached
+        let mut snapshot: Option<String> = None;
+        let mut document: Option<Value> = None;
+        let mut last_update_ts = None;
+
+        for record in records {
+            let body = self.decode(&record.body())?;
+            let kind: VersionKind = record.kind
+                .parse()
+                .map_err(StorageError::Codec)?;
+            let reconstruct_error = |e: String| StorageError::Codec(format!("Failed to reconstruct version {} of {}: {}", record.version, record.terraform_id, e));
+
+            match kind {
+                VersionKind::Snapshot => {
+                    snapshot = Some(body);
+                    document = None;
+                },
+                VersionKind::Delta => {
+                    if document.is_none() {
+                        let base = snapshot
+                            .as_deref()
+                            .ok_or_else(|| reconstruct_error("No base snapshot".to_string()))?;
+
+                        document = Some(serde_json::from_str(base).map_err(|e| reconstruct_error(e.to_string()))?);
+                    }
+
+                    if let Some(document) = document.as_mut() {
+                        history::apply(document, &body)
+                            .map_err(reconstruct_error)?;
+                    }
+                },
+            }
+
+            last_update_ts = Some(record.created_ts);
+        }
+
+        let state = document
+            .map(|document| document.to_string())
+            .or(snapshot);
+
+        Ok(state.zip(last_update_ts).map(|(state, last_update_ts)| TerraformRow {
+            id: id.as_ref().to_string(),
+            state,
+            last_update_ts,
+        }))
+    }
+
+    /// Re-encrypts every row and version not already under the primary key
+    /// with the current associated data format, returning the number of rows
+    /// rewritten. Data keys of encrypted rows are re-wrapped; plaintext rows
+    /// and rows in an older format are encrypted afresh. Rows written
+    /// concurrently are skipped as they are already up to date.
+    pub async fn rekey(&self) -> Result<u64, StorageError> {
+        let keyring = self.keyring
+            .as_ref()
+            .ok_or_else(|| StorageError::Encryption("No encryption keys configured".to_string()))?;
+
+        let rekey = |body: &StoredBody| -> Result<EncodedState, StorageError> {
+            let encrypted = match body.encrypted()? {
+                Some(encrypted) if body.aad_format == AAD_FORMAT => keyring.rewrap(&body.aad(), &encrypted)?,
+                Some(encrypted) => keyring.encrypt(&body.binding.aad(AAD_FORMAT), &keyring.decrypt(&body.aad(), &encrypted)?)?,
+                None => keyring.encrypt(&body.binding.aad(AAD_FORMAT), body.state)?,
+            };
+
+            Ok(EncodedState {
+                state: encrypted.ciphertext,
+                codec: body.codec()?,
+                key_id: Some(encrypted.key_id),
+                data_key: Some(encrypted.data_key),
+            })
+        };
+
+        let filter = "key_id IS NULL OR key_id != ?1 OR aad_format != ?2";
+        let mut rewritten = 0;
+
+        for record in self.records_where(filter, keyring.primary_key_id()).await? {
+            rewritten += self.replace_record(&record, rekey(&record.body())?).await?;
+        }
+
+        for record in self.versions_where(filter, keyring.primary_key_id()).await? {
+            rewritten += self.replace_version(&record, rekey(&record.body())?).await?;
+        }
+
+        Ok(rewritten)
+    }
+
+    /// Rewrites every row and version not encoded with the configured codec,
+    /// returning the number of rows rewritten. The `recompress` admin command
+    /// runs it after `STATE_COMPRESSION` changes, so older rows are re-encoded
+    /// once.
+    pub async fn recompress(&self) -> Result<u64, StorageError> {
+        let recompress = |body: &StoredBody| -> Result<EncodedState, StorageError> {
+            self.encode(body.binding, &self.decode(body)?)
+        };
+
+        let filter = "codec != ?1 OR aad_format != ?2";
+        let mut rewritten = 0;
+
+        for record in self.records_where(filter, self.codec.as_str()).await? {
+            rewritten += self.replace_record(&record, recompress(&record.body())?).await?;
+        }
+
+        for record in self.versions_where(filter, self.codec.as_str()).await? {
+            rewritten += self.replace_version(&record, recompress(&record.body())?).await?;
+        }
+
+        Ok(rewritten)
+    }
+
+    /// Rows matching `filter`, which is given `param` as `?1` and the current
+    /// associated data format as `?2`
+    async fn records_where(&self, filter: &str, param: &str) -> Result<Vec<TerraformRecord>, StorageError> {
+        let records = sqlx::query_as::<_, TerraformRecord>(&format!("SELECT * FROM terraform WHERE {}", filter))
+            .bind(param)
+            .bind(AAD_FORMAT)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(records)
+    }
+
+    async fn versions_where(&self, filter: &str, param: &str) -> Result<Vec<TerraformVersionRecord>, StorageError> {
+        let records = sqlx::query_as::<_, TerraformVersionRecord>(&format!("SELECT * FROM terraform_versions WHERE {}", filter))
+            .bind(param)
+            .bind(AAD_FORMAT)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(records)
+    }
+
+    /// Replaces a row only if it is unchanged since it was read
+    async fn replace_record(&self, record: &TerraformRecord, encoded: EncodedState) -> Result<u64, StorageError> {
+        let query = "UPDATE terraform SET state = ?1, codec = ?2, key_id = ?3, data_key = ?4, aad_format = ?5 \
+            WHERE id = ?6 AND state = ?7 AND codec = ?8 AND key_id IS ?9 AND aad_format = ?10";
+
+        let result = sqlx::query(query)
+            .bind(encoded.state)
+            .bind(encoded.codec.as_str())
+            .bind(encoded.key_id)
+            .bind(encoded.data_key)
+            .bind(AAD_FORMAT)
+            .bind(&record.id)
+            .bind(&record.state)
+            .bind(&record.codec)
+            .bind(&record.key_id)
+            .bind(record.aad_format)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Replaces a version's body; versions are never rewritten by writes so
+    /// no concurrency check is needed
+    async fn replace_version(&self, record: &TerraformVersionRecord, encoded: EncodedState) -> Result<u64, StorageError> {
+        let query = "UPDATE terraform_versions SET state = ?1, codec = ?2, key_id = ?3, data_key = ?4, aad_format = ?5 \
+            WHERE terraform_id = ?6 AND version = ?7";
+
+        let result = sqlx::query(query)
+            .bind(encoded.state)
+            .bind(encoded.codec.as_str())
+            .bind(encoded.key_id)
+            .bind(encoded.data_key)
+            .bind(AAD_FORMAT)
+            .bind(&record.terraform_id)
+            .bind(record.version)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Encodes a body for `binding` in the current associated data format
+    fn encode(&self, binding: Binding, state: &str) -> Result<EncodedState, StorageError> {
+        let body = self.codec
+            .encode(state.as_bytes())
+            .map_err(|e| StorageError::Codec(e.to_string()))?;
+
+        let encoded = match &self.keyring {
+            Some(keyring) => {
+                let encrypted = keyring.encrypt(&binding.aad(AAD_FORMAT), &body)?;
+
+                EncodedState {
+                    state: encrypted.ciphertext,
+                    codec: self.codec,
+                    key_id: Some(encrypted.key_id),
+                    data_key: Some(encrypted.data_key),
+                }
+            },
+            None => EncodedState {
+                state: body,
+                codec: self.codec,
+                key_id: None,
+                data_key: None,
+            },
+        };
+
+        Ok(encoded)
+    }
+
+    fn decode(&self, body: &StoredBody) -> Result<String, StorageError> {
+        let decrypted = match body.encrypted()? {
+            Some(encrypted) => {
+                let keyring = self.keyring
+                    .as_ref()
+                    .ok_or_else(|| StorageError::Encryption(format!("State {} is encrypted but no encryption keys are configured", body.binding)))?;
+
+                keyring.decrypt(&body.aad(), &encrypted)?
+            },
+            None => body.state.to_vec(),
+        };
+
+        let decoded = body
+            .codec()?
+            .decode(&decrypted)
+            .map_err(|e| StorageError::Codec(format!("Failed to decode state {}: {}", body.binding, e)))?;
+
+        String::from_utf8(decoded)
+            .map_err(|e| StorageError::Codec(e.to_string()))
+    }
+
+    fn decode_record(&self, record: TerraformRecord) -> Result<TerraformRow, StorageError> {
+        let state = self.decode(&record.body())?;
+
+        Ok(TerraformRow {
+            id: record.id,
+            state,
+            last_update_ts: record.last_update_ts,
+        })
+    }
+
+    pub async fn list(&self) -> Result<Vec<StateSummary>, SqlxError> {
+        sqlx::query_as::<_, StateSummary>("SELECT id, last_update_ts FROM terraform ORDER BY id")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    pub async fn list_locks(&self) -> Result<Vec<TerraformLockRow>, SqlxError> {
+        sqlx::query_as::<_, TerraformLockRow>("SELECT * FROM locks ORDER BY last_update_ts")
+            .fetch_all(&self.pool)
             .await
     }
 
@@ -94,23 +702,34 @@ impl TerraformQuery {
         }
     }
 
-    /// Attempts to unlock a terraform resource, returning None if the resource
-    /// lock was not found
-    pub async fn unlock<S: AsRef<str>>(&self, terraform_id: S, lock_id: S) -> Result<(), SqlxError> {
-        let query = "DELETE FROM locks WHERE id = ?1 AND terraform_id = ?2 RETURNING *";
+    /// Removes the lock on a terraform resource regardless of its lock id
+    pub async fn force_unlock<S: AsRef<str>>(&self, terraform_id: S) -> Result<Option<TerraformLockRow>, SqlxError> {
+        sqlx::query_as::<_, TerraformLockRow>("DELETE FROM locks WHERE terraform_id = ?1 RETURNING *")
+            .bind(terraform_id.as_ref())
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Removes the lock if it is held by `lock_id`, returning whether it was
+    /// removed
+    pub async fn unlock<S: AsRef<str>>(&self, terraform_id: S, lock_id: S) -> Result<bool, SqlxError> {
+        let query = "DELETE FROM locks WHERE id = ?1 AND terraform_id = ?2";
         sqlx::query(query)
             .bind(lock_id.as_ref())
             .bind(terraform_id.as_ref())
             .execute(&self.pool)
             .await
-            .map(|_| ())
+            .map(|result| result.rows_affected() > 0)
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+    };
 
     use envconfig::Envconfig;
     use sqlx::SqlitePool;
@@ -118,6 +737,9 @@ mod tests {
 
     use super::TerraformQuery;
     use crate::{
+        codec::StateCodec,
+        crypto::Keyring,
+        history::HistoryMode,
         database,
         config::Configuration,
     };
@@ -134,7 +756,7 @@ mod tests {
     }
 
     async fn get_migrated_pool(config: &Configuration) -> SqlitePool {
-        let pool = database::get_db_pool(&config)
+        let pool = database::get_db_pool(config)
             .await
             .unwrap();
 
@@ -205,15 +827,256 @@ mod tests {
             .expect("Failed to get lock")
             .expect("No lock row for resource");
 
-        query.unlock(id, lock_id)
+        let released = query.unlock(id, lock_id)
             .await
             .expect("Failed to unlock resource");
 
+        assert!(released);
+        assert!(!query.unlock(id, lock_id).await.unwrap());
         assert_eq!(lock_results.id, lock_id);
         assert_eq!(get_lock_by_tf.terraform_id, id);
 
         // Should be locked
         assert!(secondary_lock_results.is_err());
     }
-}
 
+    #[tokio::test]
+    async fn test_encryption_rekey() {
+        let config = default_config();
+        let pool = get_migrated_pool(&config)
+            .await;
+
+        let old_key = "old:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let new_key = "new:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+        let old = TerraformQuery::new(pool.clone())
+            .with_keyring(Some(Arc::new(Keyring::parse(old_key).unwrap())));
+        let rotated = TerraformQuery::new(pool.clone())
+            .with_keyring(Some(Arc::new(Keyring::parse(format!("{}\n{}", new_key, old_key)).unwrap())));
+        let new = TerraformQuery::new(pool.clone())
+            .with_keyring(Some(Arc::new(Keyring::parse(new_key).unwrap())));
+
+        TerraformQuery::new(pool.clone())
+            .create_or_replace("plaintext", "plaintext-state")
+            .await
+            .expect("Failed to create plaintext state");
+        old.create_or_replace("encrypted", "encrypted-state")
+            .await
+            .expect("Failed to create encrypted state");
+
+        let stored: (Vec<u8>,) = sqlx::query_as("SELECT state FROM terraform WHERE id = 'encrypted'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        assert!(!String::from_utf8_lossy(&stored.0).contains("encrypted-state"));
+        assert!(TerraformQuery::new(pool.clone()).get("encrypted").await.is_err());
+        assert!(new.get("encrypted").await.is_err());
+
+        let rewritten = rotated.rekey()
+            .await
+            .expect("Failed to rekey");
+
+        assert_eq!(rewritten, 2);
+        assert_eq!(new.get("encrypted").await.unwrap().unwrap().state, "encrypted-state");
+        assert_eq!(new.get("plaintext").await.unwrap().unwrap().state, "plaintext-state");
+        assert_eq!(rotated.rekey().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_aad_format() {
+        let config = default_config();
+        let pool = get_migrated_pool(&config)
+            .await;
+
+        let keyring = Arc::new(Keyring::parse("old:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=").unwrap());
+        let query = TerraformQuery::new(pool.clone())
+            .with_keyring(Some(keyring.clone()))
+            .with_history(HistoryMode::Full, 1);
+
+        query.create_or_replace("a", "version-state")
+            .await
+            .expect("Failed to create state");
+
+        // format 1 bound version 1 of `a` and the current row of `a@1` alike
+        sqlx::query("INSERT INTO terraform (id, state, codec, key_id, data_key, aad_format) \
+            SELECT 'a@1', state, codec, key_id, data_key, aad_format FROM terraform_versions WHERE terraform_id = 'a'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(query.get("a@1").await.is_err());
+
+        sqlx::query("DELETE FROM terraform WHERE id = 'a@1'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // versions encrypted before the format changed
+        let legacy = keyring.encrypt("b@1", b"legacy-state")
+            .unwrap();
+
+        sqlx::query("INSERT INTO terraform_versions (terraform_id, version, kind, state, key_id, data_key) VALUES ('b', 1, 'snapshot', ?1, ?2, ?3)")
+            .bind(&legacy.ciphertext)
+            .bind(&legacy.key_id)
+            .bind(&legacy.data_key)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(query.get_version("b", 1).await.unwrap().unwrap().state, "legacy-state");
+        assert_eq!(query.rekey().await.unwrap(), 1);
+        assert_eq!(query.rekey().await.unwrap(), 0);
+        assert_eq!(query.get_version("b", 1).await.unwrap().unwrap().state, "legacy-state");
+    }
+
+    #[tokio::test]
+    async fn test_recompress() {
+        let config = default_config();
+        let pool = get_migrated_pool(&config)
+            .await;
+
+        let keyring = Arc::new(Keyring::parse("old:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=").unwrap());
+        let state = serde_json::json!({"resources": vec!["aws_instance"; 100]})
+            .to_string();
+        let compressed = TerraformQuery::new(pool.clone())
+            .with_keyring(Some(keyring.clone()))
+            .with_codec(StateCodec::Zstd);
+
+        TerraformQuery::new(pool.clone())
+            .create_or_replace("plaintext", &state)
+            .await
+            .expect("Failed to create plaintext state");
+
+        // encrypted rows written before state bodies were stored as BLOBs
+        let legacy = keyring.encrypt("legacy", state.as_bytes())
+            .unwrap();
+
+        sqlx::query("INSERT INTO terraform (id, state, codec, key_id, data_key) VALUES ('legacy', ?1, 'base64', ?2, ?3)")
+            .bind(base64::encode(&legacy.ciphertext).into_bytes())
+            .bind(&legacy.key_id)
+            .bind(&legacy.data_key)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(compressed.get("legacy").await.unwrap().unwrap().state, state);
+        assert_eq!(compressed.recompress().await.unwrap(), 2);
+        assert_eq!(compressed.recompress().await.unwrap(), 0);
+
+        let stored: Vec<(String, Vec<u8>, String)> = sqlx::query_as("SELECT id, state, codec FROM terraform ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+
+        for (id, body, codec) in stored {
+            assert_eq!(codec, "zstd");
+            assert!(body.len() < state.len());
+            assert_eq!(compressed.get(&id).await.unwrap().unwrap().state, state);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let config = default_config();
+        let pool = get_migrated_pool(&config)
+            .await;
+
+        let keyring = Arc::new(Keyring::parse("old:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=").unwrap());
+        let query = TerraformQuery::new(pool.clone())
+            .with_keyring(Some(keyring))
+            .with_codec(StateCodec::Zstd)
+            .with_history(HistoryMode::Delta, 3);
+        let id = "105";
+        let resources = vec!["aws_instance"; 100];
+        let states: Vec<String> = (1..=7)
+            .map(|serial| serde_json::json!({"serial": serial, "resources": resources}).to_string())
+            .collect();
+
+        for state in states.iter() {
+            query.create_or_replace(id, state)
+                .await
+                .expect("Failed to write state");
+        }
+
+        let kinds: Vec<(String,)> = sqlx::query_as("SELECT kind FROM terraform_versions WHERE terraform_id = ?1 ORDER BY version")
+            .bind(id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        let kinds: Vec<&str> = kinds.iter().map(|(kind,)| kind.as_str()).collect();
+
+        assert_eq!(kinds, vec!["snapshot", "delta", "delta", "snapshot", "delta", "delta", "snapshot"]);
+        assert_eq!(query.list_versions(id).await.unwrap().len(), states.len());
+
+        for (index, state) in states.iter().enumerate() {
+            let row = query.get_version(id, index as i64 + 1)
+                .await
+                .unwrap()
+                .expect("Missing version");
+
+            assert_eq!(&row.state, state);
+        }
+
+        assert!(query.get_version(id, 8).await.unwrap().is_none());
+
+        // history written while it was disabled is not diffed against
+        TerraformQuery::new(pool.clone())
+            .create_or_replace(id, "untracked")
+            .await
+            .unwrap();
+        query.create_or_replace(id, &states[0])
+            .await
+            .unwrap();
+
+        assert_eq!(query.get_version(id, 8).await.unwrap().unwrap().state, states[0]);
+    }
+
+    #[tokio::test]
+    async fn test_history_retention() {
+        let config = default_config();
+        let pool = get_migrated_pool(&config)
+            .await;
+
+        let id = "105";
+        let resources = vec!["aws_instance"; 100];
+        let states: Vec<String> = (1..=9)
+            .map(|serial| serde_json::json!({"serial": serial, "resources": resources}).to_string())
+            .collect();
+
+        for (mode, retained) in [(HistoryMode::Full, vec![6, 7, 8, 9]), (HistoryMode::Delta, vec![4, 5, 6, 7, 8, 9])] {
+            let query = TerraformQuery::new(pool.clone())
+                .with_history(mode, 3)
+                .with_retention(4);
+
+            sqlx::query("DELETE FROM terraform_versions")
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            for state in states.iter() {
+                query.create_or_replace(id, state)
+                    .await
+                    .expect("Failed to write state");
+            }
+
+            let versions: Vec<i64> = query.list_versions(id)
+                .await
+                .unwrap()
+                .iter()
+                .map(|version| version.version)
+                .collect();
+
+            // in delta mode 4 and 5 are kept since 6 is a delta on the snapshot at 4
+            assert_eq!(versions, retained, "{}", mode);
+
+            for version in retained {
+                let row = query.get_version(id, version)
+                    .await
+                    .unwrap()
+                    .expect("Missing version");
+
+                assert_eq!(row.state, states[version as usize - 1]);
+            }
+        }
+    }
+}
@@ -14,14 +14,19 @@ use serde::Serialize;
 use serde_json::json;
 use sqlx::Error as SqlxError;
 
+use crate::storage::StorageError;
+
 
 #[derive(Debug, Clone, Serialize)]
 pub enum HttpError {
     BadGateway(String),
     BadRequest(String),
+    Conflict(String),
+    Forbidden(String),
     InternalServerError(String),
     NotFound(String),
     Unauthorized(String),
+    UnprocessableEntity(String),
 }
 
 
@@ -71,6 +76,13 @@ impl HttpError {
         Self::Unauthorized(message)
     }
 
+    pub fn forbidden(message: Option<String>) -> Self {
+        let message: String = message
+            .unwrap_or("Forbidden".to_string());
+
+        Self::Forbidden(message)
+    }
+
     pub fn bad_gateway(message: Option<String>) -> Self {
         let message: String = message
             .unwrap_or("Bad Gateway".to_string());
@@ -95,9 +107,12 @@ impl IntoResponse for HttpError {
         let (status_code, message) = match self {
             HttpError::InternalServerError(s) => (StatusCode::INTERNAL_SERVER_ERROR, s),
             HttpError::BadRequest(s) => (StatusCode::BAD_REQUEST, s),
+            HttpError::Conflict(s) => (StatusCode::CONFLICT, s),
             HttpError::Unauthorized(s) => (StatusCode::UNAUTHORIZED, s),
+            HttpError::Forbidden(s) => (StatusCode::FORBIDDEN, s),
             HttpError::BadGateway(s) => (StatusCode::BAD_GATEWAY, s),
             HttpError::NotFound(s) => (StatusCode::NOT_FOUND, s),
+            HttpError::UnprocessableEntity(s) => (StatusCode::UNPROCESSABLE_ENTITY, s),
         };
 
         (status_code, Json(json!({"message": message}))).into_response()
@@ -119,6 +134,13 @@ impl From<SqlxError> for HttpError {
 }
 
 
+impl From<StorageError> for HttpError {
+    fn from(_: StorageError) -> Self {
+        Self::bad_gateway(None)
+    }
+}
+
+
 impl From<anyhow::Error> for HttpError {
     fn from(_: anyhow::Error) -> Self {
         Self::internal_server_error(None)
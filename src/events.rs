@@ -0,0 +1,271 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+use chrono::{
+    NaiveDateTime,
+    Utc,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use tokio::sync::{
+    Notify,
+    broadcast,
+};
+
+use crate::{
+    config::SharedConfiguration,
+    webhooks,
+};
+
+
+pub type SharedEvents = Arc<Events>;
+
+
+/// Recent events kept for subscribers resuming from an earlier event id
+const REPLAY_CAPACITY: usize = 1024;
+
+
+/// Something that happened to a workspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    #[serde(rename = "state.written")]
+    StateWritten,
+    #[serde(rename = "lock.acquired")]
+    LockAcquired,
+    #[serde(rename = "lock.released")]
+    LockReleased,
+    /// An operator removed a lock held by someone else
+    #[serde(rename = "lock.force-released")]
+    LockForceReleased,
+    /// A lock has been held for longer than `STALE_LOCK_AGE`
+    #[serde(rename = "lock.stale")]
+    LockStale,
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub workspace: String,
+    pub timestamp: NaiveDateTime,
+    /// The principal whose request caused the event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    /// The lock info terraform sent when acquiring the lock
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock: Option<Value>,
+}
+
+
+/// An event numbered in the order it was published
+#[derive(Debug, Clone)]
+pub struct Sequenced {
+    pub sequence: u64,
+    pub event: Event,
+}
+
+
+/// Events to replay to a new subscriber, followed by those published after
+/// it subscribed. `resync` is set when the requested event id could not be
+/// resumed from, so the subscriber may have missed events.
+pub struct Subscription {
+    pub replay: Vec<Sequenced>,
+    pub receiver: broadcast::Receiver<Sequenced>,
+    pub resync: bool,
+}
+
+
+/// Publishes events to subscribers and to the webhooks configured at the time
+pub struct Events {
+    config: SharedConfiguration,
+    pool: Option<SqlitePool>,
+    published: Notify,
+    /// Distinguishes event ids of this process from those of earlier ones
+    epoch: i64,
+    recent: Mutex<VecDeque<Sequenced>>,
+    sender: broadcast::Sender<Sequenced>,
+}
+
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::StateWritten => "state.written",
+            Self::LockAcquired => "lock.acquired",
+            Self::LockReleased => "lock.released",
+            Self::LockForceReleased => "lock.force-released",
+            Self::LockStale => "lock.stale",
+        }
+    }
+}
+
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+
+impl Event {
+    pub fn new(event: EventKind, workspace: &str) -> Self {
+        Self {
+            event,
+            workspace: workspace.to_string(),
+            timestamp: Utc::now().naive_utc(),
+            actor: None,
+            lock: None,
+        }
+    }
+
+    pub fn with_actor(mut self, actor: &str) -> Self {
+        self.actor = Some(actor.to_string());
+        self
+    }
+
+    /// Attaches lock info, which is kept as a string if it is not JSON
+    pub fn with_lock(mut self, info: &str) -> Self {
+        self.lock = Some(serde_json::from_str(info).unwrap_or_else(|_| Value::String(info.to_string())));
+        self
+    }
+}
+
+
+impl Events {
+    /// Without a database pool there is no outbox and webhooks are not sent
+    pub fn new(config: SharedConfiguration, pool: Option<SqlitePool>) -> Self {
+        let (sender, _) = broadcast::channel(REPLAY_CAPACITY);
+
+        Self {
+            config,
+            pool,
+            published: Notify::new(),
+            epoch: Utc::now().timestamp_millis(),
+            recent: Mutex::new(VecDeque::with_capacity(REPLAY_CAPACITY)),
+            sender,
+        }
+    }
+
+    pub fn config(&self) -> &SharedConfiguration {
+        &self.config
+    }
+
+    /// Sends the event to subscribers and queues it for every matching
+    /// webhook. Failing to queue it is logged rather than failing the request
+    /// that caused it.
+    pub async fn publish(&self, event: Event) {
+        {
+            let mut recent = self.recent
+                .lock()
+                .expect("Event log lock poisoned");
+            let sequenced = Sequenced {
+                sequence: recent.back().map(|last| last.sequence + 1).unwrap_or(1),
+                event: event.clone(),
+            };
+
+            if recent.len() == REPLAY_CAPACITY {
+                recent.pop_front();
+            }
+
+            recent.push_back(sequenced.clone());
+
+            // only fails when nobody is subscribed
+            self.sender.send(sequenced).ok();
+        }
+
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return,
+        };
+
+        let settings = self.config.current();
+
+        match webhooks::enqueue(pool, &settings.webhooks, &event).await {
+            Ok(0) => (),
+            Ok(_) => self.published.notify_one(),
+            Err(e) => tracing::error!("Failed to queue {} webhooks for {}: {}", event.event, event.workspace, e),
+        }
+    }
+
+    /// Subscribes to events published from now on, first replaying those
+    /// after `last_event_id` if it is given
+    pub fn subscribe(&self, last_event_id: Option<&str>) -> Subscription {
+        // holding the log while subscribing means no event is both replayed
+        // and received, or neither
+        let recent = self.recent
+            .lock()
+            .expect("Event log lock poisoned");
+        let receiver = self.sender.subscribe();
+
+        let last = match last_event_id {
+            Some(id) => id,
+            None => return Subscription {
+                replay: Vec::new(),
+                receiver,
+                resync: false,
+            },
+        };
+
+        let latest = recent
+            .back()
+            .map(|last| last.sequence)
+            .unwrap_or(0);
+        let oldest = recent
+            .front()
+            .map(|first| first.sequence)
+            .unwrap_or(latest + 1);
+        let resumable = self.parse_id(last)
+            .filter(|sequence| *sequence + 1 >= oldest && *sequence <= latest);
+
+        match resumable {
+            Some(sequence) => Subscription {
+                replay: recent
+                    .iter()
+                    .filter(|event| event.sequence > sequence)
+                    .cloned()
+                    .collect(),
+                receiver,
+                resync: false,
+            },
+            None => Subscription {
+                replay: Vec::new(),
+                receiver,
+                resync: true,
+            },
+        }
+    }
+
+    /// The id sent to subscribers for an event
+    pub fn id(&self, sequence: u64) -> String {
+        format!("{}-{}", self.epoch, sequence)
+    }
+
+    fn parse_id(&self, id: &str) -> Option<u64> {
+        let (epoch, sequence) = id.split_once('-')?;
+
+        if epoch.parse::<i64>().ok()? != self.epoch {
+            return None;
+        }
+
+        sequence
+            .parse()
+            .ok()
+    }
+
+    /// Resolves once an event has been queued since the last call
+    pub async fn published(&self) {
+        self.published
+            .notified()
+            .await
+    }
+}
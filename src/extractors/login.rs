@@ -9,13 +9,19 @@ use http::header::AUTHORIZATION;
 use http_auth_basic::Credentials;
 
 use crate::{
+    auth::{
+        Policy,
+        Principal,
+    },
     config::SharedConfiguration,
     error::HttpError,
+    metrics::SharedMetrics,
 };
 
 
+/// Authenticates the caller by basic auth or a bearer token
 #[derive(Debug)]
-pub struct LoginExtractor(pub Credentials);
+pub struct LoginExtractor(pub Principal);
 
 
 #[async_trait]
@@ -32,22 +38,27 @@ where
             .get()
             .expect("Failed to get SharedConfiguration from Extensions");
 
-        let credentials = credentials_from_request(request)?;
-        let expected_credentials = Credentials::new(
-            &config.tf_http_username,
-            &config.tf_http_password,
-        );
+        let settings = config.current();
+
+        match principal_from_request(request, &settings.policy) {
+            Ok(principal) => Ok(Self(principal)),
+            Err(e) => {
+                let metrics: Option<&SharedMetrics> = request
+                    .extensions()
+                    .and_then(|extensions| extensions.get());
+
+                if let Some(metrics) = metrics {
+                    metrics.auth_failures.inc();
+                }
 
-        if credentials == expected_credentials {
-            Ok(Self(credentials))
-        } else {
-            Err(HttpError::unauthorized(None))
+                Err(e)
+            },
         }
     }
 }
 
 
-fn credentials_from_request<B>(request: &RequestParts<B>) -> Result<Credentials, HttpError> {
+fn principal_from_request<B>(request: &RequestParts<B>, policy: &Policy) -> Result<Principal, HttpError> {
     let header = request
         .headers()
         .ok_or(HttpError::internal_server_error(None))?
@@ -56,6 +67,15 @@ fn credentials_from_request<B>(request: &RequestParts<B>) -> Result<Credentials,
         .to_str()
         .map_err(|_| HttpError::unauthorized(None))?;
 
-    Credentials::from_header(header.to_owned())
-        .map_err(HttpError::from)
+    let principal = match header.strip_prefix("Bearer ") {
+        Some(token) => policy.authenticate_token(token.trim()),
+        None => {
+            let credentials = Credentials::from_header(header.to_owned())
+                .map_err(HttpError::from)?;
+
+            policy.authenticate_basic(&credentials.user_id, &credentials.password)
+        },
+    };
+
+    principal.ok_or(HttpError::unauthorized(None))
 }
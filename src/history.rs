@@ -0,0 +1,159 @@
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+use json_patch::Patch;
+use serde_json::Value;
+
+
+/// How previous versions of a state are retained
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryMode {
+    /// Only the current state is kept
+    None,
+    /// Every version is stored in full
+    Full,
+    /// Periodic full snapshots with JSON patches against the previous version
+    /// in between
+    Delta,
+}
+
+
+/// How a stored version is reconstructed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VersionKind {
+    Snapshot,
+    Delta,
+}
+
+
+impl FromStr for HistoryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "full" => Ok(Self::Full),
+            "delta" => Ok(Self::Delta),
+            other => Err(format!("Unknown history mode: {}", other)),
+        }
+    }
+}
+
+
+impl fmt::Display for HistoryMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            Self::None => "none",
+            Self::Full => "full",
+            Self::Delta => "delta",
+        };
+
+        write!(f, "{}", mode)
+    }
+}
+
+
+impl VersionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Snapshot => "snapshot",
+            Self::Delta => "delta",
+        }
+    }
+}
+
+
+impl FromStr for VersionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshot" => Ok(Self::Snapshot),
+            "delta" => Ok(Self::Delta),
+            other => Err(format!("Unknown version kind: {}", other)),
+        }
+    }
+}
+
+
+/// Returns a JSON patch from `previous` to `next`, or None if either is not
+/// JSON or the patch would not be smaller than `next` itself
+pub fn diff(previous: &str, next: &str) -> Option<String> {
+    let previous: Value = serde_json::from_str(previous).ok()?;
+    let next_value: Value = serde_json::from_str(next).ok()?;
+
+    // a patch only reproduces `next` byte for byte if it was serialized the
+    // same way it will be when the patch is applied
+    let serialized = next_value.to_string();
+
+    if serialized != next {
+        return None;
+    }
+
+    let patch = serde_json::to_string(&json_patch::diff(&previous, &next_value))
+        .ok()?;
+
+    if patch.len() < next.len() {
+        Some(patch)
+    } else {
+        None
+    }
+}
+
+
+/// Applies a patch produced by `diff` to the previous version in place
+pub fn apply(document: &mut Value, patch: &str) -> Result<(), String> {
+    let patch: Patch = serde_json::from_str(patch)
+        .map_err(|e| format!("Malformed patch: {}", e))?;
+
+    json_patch::patch(document, &patch)
+        .map_err(|e| format!("Failed to apply patch: {}", e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{
+        apply,
+        diff,
+    };
+
+    #[test]
+    fn test_diff_apply() {
+        let resources: Vec<_> = (0..50)
+            .map(|i| json!({"type": "aws_instance", "name": format!("web-{}", i)}))
+            .collect();
+        let previous = json!({"serial": 1, "resources": resources})
+            .to_string();
+        let next = json!({"serial": 2, "resources": resources})
+            .to_string();
+
+        let patch = diff(&previous, &next)
+            .expect("Expected a patch");
+
+        let mut document = serde_json::from_str(&previous)
+            .unwrap();
+
+        apply(&mut document, &patch)
+            .unwrap();
+
+        assert!(patch.len() < next.len());
+        assert_eq!(document.to_string(), next);
+    }
+
+    #[test]
+    fn test_diff_fallback() {
+        // not JSON
+        assert!(diff("state", "other-state").is_none());
+
+        // not serialized the way patched documents are
+        assert!(diff("{\"a\": 1}", "{\"a\": 2, \"b\": [ 1 ]}").is_none());
+
+        // patch larger than the document
+        assert!(diff("{\"a\":1}", "{\"b\":2}").is_none());
+    }
+}
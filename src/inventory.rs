@@ -0,0 +1,926 @@
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+        HashMap,
+        HashSet,
+    },
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        RwLock,
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+    },
+    time::Duration,
+};
+
+use chrono::{
+    NaiveDateTime,
+    Utc,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+
+use crate::{
+    auth::glob_match,
+    models::state::{
+        ResourceMode,
+        State,
+    },
+    secrets::{
+        self,
+        Finding,
+    },
+    storage::{
+        SharedStorage,
+        StorageError,
+    },
+};
+
+
+pub type SharedInventory = Arc<Inventory>;
+
+
+/// Instance attributes that identify the object a resource manages
+const INDEXED_ATTRIBUTES: &[&str] = &["id", "arn", "name"];
+
+/// Search parameters naming an indexed attribute start with this
+const ATTRIBUTE_PREFIX: &str = "attr.";
+
+/// How often storage is checked for states written other than through the
+/// API, such as by the admin commands
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+
+/// One resource instance and where it is managed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexedResource {
+    pub workspace: String,
+    pub address: String,
+    pub mode: ResourceMode,
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub provider: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// Those of `id`, `arn` and `name` the instance has
+    pub attributes: BTreeMap<String, String>,
+}
+
+
+/// Which resources to return; every given criterion has to match
+#[derive(Debug, Default, PartialEq)]
+pub struct Search {
+    /// A pattern in which `*` matches any run of characters
+    pub workspace: Option<String>,
+    /// A pattern in which `*` matches any run of characters
+    pub address: Option<String>,
+    pub resource_type: Option<String>,
+    /// A full source address, or its trailing components such as `hashicorp/aws`
+    pub provider: Option<String>,
+    /// A module address; empty for the root module
+    pub module: Option<String>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct SearchError(pub String);
+
+
+/// What to do when a posted state manages an object another workspace
+/// already manages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Allow,
+    /// Accept the state and log a warning
+    Warn,
+    /// Reject the state with 409
+    Reject,
+}
+
+
+/// Managed resource instances of the same type and `id` in more than one
+/// workspace, which will fight over the object on every apply
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Duplicate {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub id: String,
+    pub owners: Vec<Owner>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Owner {
+    pub workspace: String,
+    pub address: String,
+}
+
+
+/// The terraform and providers a workspace's current state was written with.
+/// States do not record provider versions, which live in the configuration's
+/// dependency lock file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkspaceVersions {
+    pub workspace: String,
+    pub state_version: u64,
+    pub terraform_version: Option<String>,
+    /// Source addresses of the providers of the state's resources
+    pub providers: BTreeSet<String>,
+}
+
+
+/// Which workspaces `GET /inventory/versions` reports on; every given
+/// criterion has to match
+#[derive(Debug, Default, Deserialize)]
+pub struct VersionFilter {
+    /// A pattern in which `*` matches any run of characters
+    pub workspace: Option<String>,
+    /// A pattern matched against the terraform version, such as `0.13.*`
+    pub terraform: Option<String>,
+    /// Only states written by a terraform release before this one
+    pub terraform_below: Option<String>,
+    /// A provider any resource uses, in the form `GET /search` accepts
+    pub provider: Option<String>,
+}
+
+
+/// The resources of every stored state, kept up to date as states are written
+/// through the API and refreshed from storage for states written otherwise
+pub struct Inventory {
+    workspaces: RwLock<HashMap<String, Indexed>>,
+    generation: AtomicU64,
+}
+
+
+/// What is known about one workspace's current state
+struct Indexed {
+    versions: WorkspaceVersions,
+    resources: Vec<IndexedResource>,
+    findings: Vec<Finding>,
+    /// When the indexed state was stored, if no later write can share that
+    /// timestamp; otherwise the next refresh reads the state again
+    stored_at: Option<NaiveDateTime>,
+    /// Changes whenever the entry is replaced, so a refresh never overwrites
+    /// a state indexed while it was reading an older one
+    generation: u64,
+}
+
+
+impl Search {
+    /// Reads the query string of `GET /search`, where `attr.<name>` matches an
+    /// indexed attribute
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, SearchError> {
+        let mut search = Self::default();
+
+        for (key, value) in params {
+            match key.as_str() {
+                "workspace" => search.workspace = Some(value.clone()),
+                "address" => search.address = Some(value.clone()),
+                "type" => search.resource_type = Some(value.clone()),
+                "provider" => search.provider = Some(value.clone()),
+                "module" => search.module = Some(value.clone()),
+                key => match key.strip_prefix(ATTRIBUTE_PREFIX) {
+                    Some(name) if INDEXED_ATTRIBUTES.contains(&name) => {
+                        search.attributes.insert(name.to_string(), value.clone());
+                    },
+                    Some(name) => {
+                        return Err(SearchError(format!("Attribute {} is not indexed; use one of {}", name, INDEXED_ATTRIBUTES.join(", "))));
+                    },
+                    None => return Err(SearchError(format!("Unknown search parameter {}", key))),
+                },
+            }
+        }
+
+        if search == Self::default() {
+            return Err(SearchError("At least one search parameter is required".to_string()));
+        }
+
+        Ok(search)
+    }
+
+    pub fn matches(&self, resource: &IndexedResource) -> bool {
+        self.workspace.as_ref().map(|pattern| glob_match(pattern, &resource.workspace)).unwrap_or(true)
+            && self.address.as_ref().map(|pattern| glob_match(pattern, &resource.address)).unwrap_or(true)
+            && self.resource_type.as_ref().map(|resource_type| resource.resource_type == *resource_type).unwrap_or(true)
+            && self.provider.as_ref().map(|provider| provider_matches(provider, &resource.provider)).unwrap_or(true)
+            && self.module.as_ref().map(|module| resource.module.as_deref().unwrap_or_default() == module).unwrap_or(true)
+            && self.attributes.iter().all(|(name, value)| resource.attributes.get(name) == Some(value))
+    }
+}
+
+
+impl WorkspaceVersions {
+    fn from_state(workspace: &str, state: &State) -> Self {
+        Self {
+            workspace: workspace.to_string(),
+            state_version: state.version,
+            terraform_version: Some(state.terraform_version.clone()),
+            providers: state.resources
+                .iter()
+                .map(|resource| resource.provider_source().to_string())
+                .collect(),
+        }
+    }
+
+    /// Reads what an older state format records, such as the `provider.aws`
+    /// of version 3 resources
+    fn from_legacy(workspace: &str, state: &Value) -> Option<Self> {
+        let providers = state
+            .get("modules")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|module| module.get("resources").and_then(Value::as_object))
+            .flat_map(|resources| resources.values())
+            .filter_map(|resource| resource.get("provider").and_then(Value::as_str))
+            .filter(|provider| !provider.is_empty())
+            .map(|provider| provider.to_string())
+            .collect();
+
+        Some(Self {
+            workspace: workspace.to_string(),
+            state_version: state.get("version")?.as_u64()?,
+            terraform_version: state
+                .get("terraform_version")
+                .and_then(Value::as_str)
+                .map(|version| version.to_string()),
+            providers,
+        })
+    }
+}
+
+
+impl VersionFilter {
+    /// Fails if `terraform_below` is not a version
+    pub fn validate(&self) -> Result<(), SearchError> {
+        match &self.terraform_below {
+            Some(version) if parse_version(version).is_none() => Err(SearchError(format!("{} is not a terraform version", version))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, versions: &WorkspaceVersions) -> bool {
+        let terraform = versions.terraform_version
+            .as_deref()
+            .unwrap_or_default();
+
+        self.workspace.as_ref().map(|pattern| glob_match(pattern, &versions.workspace)).unwrap_or(true)
+            && self.terraform.as_ref().map(|pattern| glob_match(pattern, terraform)).unwrap_or(true)
+            && self.terraform_below.as_ref().map(|below| older_than(terraform, below)).unwrap_or(true)
+            && self.provider.as_ref().map(|provider| versions.providers.iter().any(|source| provider_matches(provider, source))).unwrap_or(true)
+    }
+}
+
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+
+impl std::error::Error for SearchError {}
+
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "warn" => Ok(Self::Warn),
+            "reject" => Ok(Self::Reject),
+            other => Err(format!("Unknown duplicate resource policy: {}", other)),
+        }
+    }
+}
+
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = match self {
+            Self::Allow => "allow",
+            Self::Warn => "warn",
+            Self::Reject => "reject",
+        };
+
+        write!(f, "{}", policy)
+    }
+}
+
+
+impl fmt::Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let owners: Vec<_> = self.owners
+            .iter()
+            .map(|owner| format!("{} in {}", owner.address, owner.workspace))
+            .collect();
+
+        write!(f, "{} {} is managed by {}", self.resource_type, self.id, owners.join(" and "))
+    }
+}
+
+
+impl Inventory {
+    pub fn new() -> Self {
+        Self {
+            workspaces: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Indexes every stored state, replacing what was indexed before. Only
+    /// the versions of states older than version 4 are indexed, not their
+    /// resources. Returns how many workspaces were indexed.
+    pub async fn rebuild(&self, storage: &SharedStorage) -> Result<usize, StorageError> {
+        self.workspaces
+            .write()
+            .expect("Inventory lock poisoned")
+            .clear();
+
+        self.refresh(storage)
+            .await?;
+
+        Ok(self.workspaces.read().expect("Inventory lock poisoned").len())
+    }
+
+    /// Indexes the stored states written since they were last indexed, such
+    /// as by the admin commands, and forgets those no longer stored. Returns
+    /// how many workspaces were indexed.
+    pub async fn refresh(&self, storage: &SharedStorage) -> Result<usize, StorageError> {
+        let summaries = storage
+            .list()
+            .await?;
+        let known: HashMap<String, (Option<NaiveDateTime>, u64)> = self.workspaces
+            .read()
+            .expect("Inventory lock poisoned")
+            .iter()
+            .map(|(id, indexed)| (id.clone(), (indexed.stored_at, indexed.generation)))
+            .collect();
+        let mut indexed_count = 0;
+
+        for summary in summaries.iter() {
+            let seen = known.get(&summary.id);
+
+            if let Some((Some(stored_at), _)) = seen {
+                if *stored_at == summary.last_update_ts {
+                    continue;
+                }
+            }
+
+            let row = match storage.get(&summary.id).await? {
+                Some(row) => row,
+                None => continue,
+            };
+
+            // timestamps have a resolution of a second, so a write in the
+            // same second as this one would look unchanged
+            let settled = Utc::now().naive_utc() - chrono::Duration::seconds(1);
+            let indexed = Indexed::from_stored(&summary.id, &row.state)
+                .map(|mut indexed| {
+                    indexed.stored_at = Some(row.last_update_ts).filter(|stored_at| *stored_at < settled);
+                    indexed
+                });
+            let mut workspaces = self.workspaces
+                .write()
+                .expect("Inventory lock poisoned");
+
+            // a state written through the API meanwhile is newer than this one
+            if workspaces.get(&summary.id).map(|indexed| indexed.generation) != seen.map(|(_, generation)| *generation) {
+                continue;
+            }
+
+            match indexed {
+                Some(indexed) => {
+                    self.insert(&mut workspaces, &summary.id, indexed);
+                    indexed_count += 1;
+                },
+                None => {
+                    workspaces.remove(&summary.id);
+                },
+            }
+        }
+
+        let stored: HashSet<_> = summaries
+            .iter()
+            .map(|summary| summary.id.as_str())
+            .collect();
+
+        self.workspaces
+            .write()
+            .expect("Inventory lock poisoned")
+            .retain(|id, indexed| {
+                stored.contains(id.as_str()) || known.get(id).map(|(_, generation)| *generation) != Some(indexed.generation)
+            });
+
+        Ok(indexed_count)
+    }
+
+    /// Replaces what is indexed for a workspace with the resources of the
+    /// state just written to it
+    pub fn update(&self, workspace: &str, state: &State) {
+        let mut workspaces = self.workspaces
+            .write()
+            .expect("Inventory lock poisoned");
+
+        self.insert(&mut workspaces, workspace, Indexed::new(workspace, state));
+    }
+
+    fn insert(&self, workspaces: &mut HashMap<String, Indexed>, workspace: &str, mut indexed: Indexed) {
+        indexed.generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+
+        workspaces.insert(workspace.to_string(), indexed);
+    }
+
+    /// The matching resources ordered by workspace and address
+    pub fn search(&self, search: &Search) -> Vec<IndexedResource> {
+        let mut found: Vec<_> = self.workspaces
+            .read()
+            .expect("Inventory lock poisoned")
+            .values()
+            .flat_map(|indexed| &indexed.resources)
+            .filter(|resource| search.matches(resource))
+            .cloned()
+            .collect();
+
+        found.sort_by(|a, b| (&a.workspace, &a.address).cmp(&(&b.workspace, &b.address)));
+
+        found
+    }
+
+    /// Every object managed by more than one workspace, ordered by type and id
+    pub fn duplicates(&self) -> Vec<Duplicate> {
+        let workspaces = self.workspaces
+            .read()
+            .expect("Inventory lock poisoned");
+
+        find_duplicates(workspaces.values().flat_map(|indexed| &indexed.resources))
+    }
+
+    /// The objects a state about to be written to `workspace` would manage
+    /// alongside another workspace
+    pub fn duplicates_of(&self, workspace: &str, state: &State) -> Vec<Duplicate> {
+        let resources = index(workspace, state);
+        let workspaces = self.workspaces
+            .read()
+            .expect("Inventory lock poisoned");
+        let others = workspaces
+            .iter()
+            .filter(|(other, _)| *other != workspace)
+            .flat_map(|(_, indexed)| &indexed.resources);
+
+        find_duplicates(resources.iter().chain(others))
+            .into_iter()
+            .filter(|duplicate| duplicate.owners.iter().any(|owner| owner.workspace == workspace))
+            .collect()
+    }
+
+    /// The versions of the matching workspaces ordered by workspace
+    pub fn versions(&self, filter: &VersionFilter) -> Vec<WorkspaceVersions> {
+        let mut found: Vec<_> = self.workspaces
+            .read()
+            .expect("Inventory lock poisoned")
+            .values()
+            .map(|indexed| &indexed.versions)
+            .filter(|versions| filter.matches(versions))
+            .cloned()
+            .collect();
+
+        found.sort_by(|a, b| a.workspace.cmp(&b.workspace));
+
+        found
+    }
+
+    /// What looks like a secret in a workspace's current state, or `None` if
+    /// the workspace is not indexed. States older than version 4 are not
+    /// scanned.
+    pub fn findings(&self, workspace: &str) -> Option<Vec<Finding>> {
+        self.workspaces
+            .read()
+            .expect("Inventory lock poisoned")
+            .get(workspace)
+            .map(|indexed| indexed.findings.clone())
+    }
+}
+
+
+impl Indexed {
+    fn new(workspace: &str, state: &State) -> Self {
+        Self {
+            versions: WorkspaceVersions::from_state(workspace, state),
+            resources: index(workspace, state),
+            findings: secrets::scan(state),
+            stored_at: None,
+            generation: 0,
+        }
+    }
+
+    /// Indexes a stored document, or only the versions of a state older than
+    /// version 4; `None` if it is not a terraform state
+    fn from_stored(workspace: &str, stored: &str) -> Option<Self> {
+        let value = match serde_json::from_str::<Value>(stored) {
+            Ok(value) => value,
+            Err(_) => {
+                tracing::debug!("Not indexing {}, which is not JSON", workspace);
+                return None;
+            },
+        };
+
+        let indexed = match State::from_value(&value) {
+            Ok(state) => Some(Self::new(workspace, &state)),
+            Err(_) => WorkspaceVersions::from_legacy(workspace, &value).map(|versions| Self {
+                versions,
+                resources: Vec::new(),
+                findings: Vec::new(),
+                stored_at: None,
+                generation: 0,
+            }),
+        };
+
+        if indexed.is_none() {
+            tracing::debug!("Not indexing {}, which is not a terraform state", workspace);
+        }
+
+        indexed
+    }
+}
+
+
+impl Default for Inventory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Refreshes `inventory` from `storage` until the server stops
+pub async fn keep_fresh(inventory: SharedInventory, storage: SharedStorage) {
+    let mut ticks = tokio::time::interval(REFRESH_INTERVAL);
+
+    // the first tick completes at once, right after the startup rebuild
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+
+        match inventory.refresh(&storage).await {
+            Ok(0) => (),
+            Ok(count) => tracing::debug!("Indexed {} states written outside the API", count),
+            Err(e) => tracing::error!("Failed to refresh the inventory: {}", e),
+        }
+    }
+}
+
+
+/// One entry per resource instance of the state
+pub fn index(workspace: &str, state: &State) -> Vec<IndexedResource> {
+    let mut resources = Vec::new();
+
+    for resource in state.resources.iter() {
+        for instance in resource.instances.iter() {
+            let mut attributes = BTreeMap::new();
+
+            for name in INDEXED_ATTRIBUTES {
+                let value = match instance.attributes.as_ref().and_then(|attributes| attributes.get(name)) {
+                    Some(Value::String(value)) => Some(value.clone()),
+                    Some(Value::Number(value)) => Some(value.to_string()),
+                    _ => instance.attributes_flat.as_ref().and_then(|attributes| attributes.get(*name)).cloned(),
+                };
+
+                if let Some(value) = value.filter(|value| !value.is_empty()) {
+                    attributes.insert(name.to_string(), value);
+                }
+            }
+
+            resources.push(IndexedResource {
+                workspace: workspace.to_string(),
+                address: resource.address(instance),
+                mode: resource.mode,
+                resource_type: resource.resource_type.clone(),
+                provider: resource.provider_source().to_string(),
+                module: resource.module.clone(),
+                attributes,
+            });
+        }
+    }
+
+    resources
+}
+
+
+/// Whether `filter` is the provider's full source address or its trailing
+/// components, such as `hashicorp/aws` or `aws`
+fn provider_matches(filter: &str, source: &str) -> bool {
+    source == filter || source.ends_with(&format!("/{}", filter))
+}
+
+
+/// Whether `version` is a release before `than`, comparing numeric
+/// components so that `0.9.0` is before `0.13.0`. A missing or unreadable
+/// version counts as older than any.
+pub fn older_than(version: &str, than: &str) -> bool {
+    match (parse_version(version), parse_version(than)) {
+        (Some(version), Some(than)) => version < than,
+        (None, Some(_)) => true,
+        _ => false,
+    }
+}
+
+
+/// The numeric components of a version such as `v1.3.0-beta1`, padded to
+/// major, minor and patch so `1.3` and `1.3.0` compare equal
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let release = version
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()?;
+    let mut components = release
+        .split('.')
+        .map(|component| component.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    if components.len() > 3 {
+        return None;
+    }
+
+    components.resize(3, 0);
+
+    Some(components)
+}
+
+
+fn find_duplicates<'a>(resources: impl Iterator<Item = &'a IndexedResource>) -> Vec<Duplicate> {
+    let mut objects: BTreeMap<(&str, &str), Vec<Owner>> = BTreeMap::new();
+
+    // data sources read objects without managing them
+    for resource in resources.filter(|resource| resource.mode == ResourceMode::Managed) {
+        if let Some(id) = resource.attributes.get("id") {
+            objects.entry((&resource.resource_type, id))
+                .or_default()
+                .push(Owner {
+                    workspace: resource.workspace.clone(),
+                    address: resource.address.clone(),
+                });
+        }
+    }
+
+    objects
+        .into_iter()
+        .filter_map(|((resource_type, id), mut owners)| {
+            owners.sort_by(|a, b| (&a.workspace, &a.address).cmp(&(&b.workspace, &b.address)));
+
+            let workspaces: Vec<_> = owners
+                .iter()
+                .map(|owner| &owner.workspace)
+                .collect();
+
+            // several instances of one workspace are its own business
+            if workspaces.windows(2).all(|pair| pair[0] == pair[1]) {
+                return None;
+            }
+
+            Some(Duplicate {
+                resource_type: resource_type.to_string(),
+                id: id.to_string(),
+                owners,
+            })
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+    };
+
+    use serde_json::json;
+    use tokio;
+
+    use super::{
+        Inventory,
+        Search,
+        VersionFilter,
+        older_than,
+    };
+    use crate::{
+        models::state::State,
+        storage::{
+            MemoryStorage,
+            SharedStorage,
+        },
+    };
+
+    fn state(bucket: &str) -> State {
+        State::from_value(&json!({
+            "version": 4,
+            "terraform_version": "1.0.11",
+            "serial": 1,
+            "lineage": "abcd",
+            "resources": [
+                {
+                    "mode": "managed",
+                    "type": "aws_s3_bucket",
+                    "name": "logs",
+                    "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+                    "instances": [{"schema_version": 0, "attributes": {"id": bucket, "arn": format!("arn:aws:s3:::{}", bucket)}}],
+                },
+                {
+                    "module": "module.dns",
+                    "mode": "managed",
+                    "type": "aws_route53_record",
+                    "name": "www",
+                    "each": "map",
+                    "provider": "provider[\"registry.terraform.io/hashicorp/aws\"].west",
+                    "instances": [{"index_key": "a", "schema_version": 2, "attributes": {"id": format!("Z123_{}", bucket), "name": "www"}}],
+                },
+            ],
+        }))
+            .unwrap()
+    }
+
+    fn search(params: &[(&str, &str)]) -> Search {
+        let params: HashMap<_, _> = params
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Search::from_params(&params)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_inventory() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+
+        storage.create_or_replace("prod-logs", &serde_json::to_string(&state("prod-logs")).unwrap())
+            .await
+            .unwrap();
+        storage.create_or_replace("legacy", "{\"version\": 3}")
+            .await
+            .unwrap();
+
+        let inventory = Inventory::new();
+
+        // the legacy state is indexed without resources
+        assert_eq!(inventory.rebuild(&storage).await.unwrap(), 2);
+
+        inventory.update("staging-logs", &state("staging-logs"));
+
+        let found = inventory.search(&search(&[("type", "aws_s3_bucket"), ("attr.id", "prod-logs")]));
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].workspace, "prod-logs");
+        assert_eq!(found[0].address, "aws_s3_bucket.logs");
+        assert_eq!(found[0].attributes["arn"], "arn:aws:s3:::prod-logs");
+
+        let found = inventory.search(&search(&[("provider", "hashicorp/aws"), ("module", "module.dns")]));
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].address, "module.dns.aws_route53_record.www[\"a\"]");
+        assert_eq!(found[0].provider, "registry.terraform.io/hashicorp/aws");
+
+        assert_eq!(inventory.search(&search(&[("workspace", "staging-*"), ("module", "")])).len(), 1);
+
+        // a newer state replaces what was indexed
+        inventory.update("prod-logs", &state("renamed"));
+
+        assert!(inventory.search(&search(&[("attr.id", "prod-logs")])).is_empty());
+
+        for params in [vec![], vec![("attr.tags", "x")], vec![("color", "blue")]] {
+            let params: HashMap<_, _> = params
+                .into_iter()
+                .map(|(key, value): (&str, &str)| (key.to_string(), value.to_string()))
+                .collect();
+
+            assert!(Search::from_params(&params).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+
+        storage.create_or_replace("prod-logs", &serde_json::to_string(&state("prod-logs")).unwrap())
+            .await
+            .unwrap();
+
+        let inventory = Inventory::new();
+
+        assert_eq!(inventory.rebuild(&storage).await.unwrap(), 1);
+
+        // written behind the server's back, as the admin commands do
+        storage.create_or_replace("audit", &serde_json::to_string(&state("audit-logs")).unwrap())
+            .await
+            .unwrap();
+        storage.create_or_replace("prod-logs", &serde_json::to_string(&state("renamed")).unwrap())
+            .await
+            .unwrap();
+
+        assert!(inventory.search(&search(&[("attr.id", "audit-logs")])).is_empty());
+
+        inventory.refresh(&storage)
+            .await
+            .unwrap();
+
+        assert_eq!(inventory.search(&search(&[("attr.id", "audit-logs")])).len(), 1);
+        assert!(inventory.search(&search(&[("attr.id", "prod-logs")])).is_empty());
+
+        // a workspace that is not stored is forgotten
+        inventory.update("staging-logs", &state("staging-logs"));
+        inventory.refresh(&storage)
+            .await
+            .unwrap();
+
+        assert_eq!(inventory.versions(&VersionFilter::default()).len(), 2);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let inventory = Inventory::new();
+
+        inventory.update("prod-logs", &state("logs"));
+        inventory.update("staging-logs", &state("staging-logs"));
+
+        assert!(inventory.duplicates().is_empty());
+
+        // importing the bucket prod-logs already manages
+        let imported = state("logs");
+        let found = inventory.duplicates_of("audit", &imported);
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].to_string(), "aws_s3_bucket logs is managed by aws_s3_bucket.logs in audit and aws_s3_bucket.logs in prod-logs");
+
+        // as is rewriting a workspace which already manages it
+        assert!(inventory.duplicates_of("prod-logs", &imported).is_empty());
+
+        inventory.update("audit", &imported);
+
+        let found = inventory.duplicates();
+
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].resource_type.as_str(), found[0].id.as_str()), ("aws_route53_record", "Z123_logs"));
+        assert_eq!(found[0].owners.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+
+        storage.create_or_replace("legacy", &json!({
+            "version": 3,
+            "terraform_version": "0.11.14",
+            "serial": 1,
+            "lineage": "abcd",
+            "modules": [{"path": ["root"], "resources": {"aws_instance.web": {"type": "aws_instance", "provider": "provider.aws"}}}],
+        }).to_string())
+            .await
+            .unwrap();
+
+        let inventory = Inventory::new();
+
+        inventory.rebuild(&storage)
+            .await
+            .unwrap();
+        inventory.update("current", &state("logs"));
+
+        let all = inventory.versions(&VersionFilter::default());
+
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].workspace, "current");
+        assert_eq!(all[0].providers.iter().collect::<Vec<_>>(), vec!["registry.terraform.io/hashicorp/aws"]);
+        assert_eq!(all[1].terraform_version.as_deref(), Some("0.11.14"));
+        assert!(all[1].providers.contains("provider.aws"));
+
+        let filter = VersionFilter {
+            terraform_below: Some("0.12".to_string()),
+            ..VersionFilter::default()
+        };
+
+        assert_eq!(inventory.versions(&filter)[0].workspace, "legacy");
+
+        let filter = VersionFilter {
+            provider: Some("hashicorp/aws".to_string()),
+            terraform: Some("1.*".to_string()),
+            ..VersionFilter::default()
+        };
+
+        assert_eq!(inventory.versions(&filter).len(), 1);
+
+        assert!(older_than("0.9.0", "0.13"));
+        assert!(older_than("1.3.0-beta1", "v1.4.0"));
+        assert!(!older_than("1.3", "1.3.0"));
+        assert!(VersionFilter { terraform_below: Some("three".to_string()), ..VersionFilter::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,26 @@
+pub mod admin;
+pub mod admission;
+pub mod api;
+pub mod archive;
+pub mod auth;
+pub mod backup;
+pub mod cli;
+pub mod codec;
+pub mod config;
+pub mod crypto;
+pub mod database;
+pub mod db;
+pub mod error;
+pub mod events;
+pub mod extractors;
+pub mod history;
+pub mod inventory;
+pub mod lifecycle;
+pub mod local_backend;
+pub mod metrics;
+pub mod models;
+pub mod routes;
+pub mod rules;
+pub mod secrets;
+pub mod storage;
+pub mod webhooks;
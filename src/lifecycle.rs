@@ -0,0 +1,210 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+    },
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+use axum::extract::MatchedPath;
+use futures::future::BoxFuture;
+use http::{
+    Method,
+    Request,
+};
+use tokio::sync::Notify;
+use tower::{
+    Layer,
+    Service,
+};
+
+
+pub type SharedLifecycle = Arc<Lifecycle>;
+
+
+/// Route whose POSTs upload state; these are the requests worth draining
+const STATE_ROUTE: &str = "/terraform/:id";
+
+
+/// Server lifecycle shared between the API and whatever stops the server
+#[derive(Debug, Default)]
+pub struct Lifecycle {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    in_flight_uploads: AtomicUsize,
+    drain_started: Notify,
+}
+
+
+/// Counts requests for the duration of their handling
+#[derive(Clone)]
+pub struct InFlightLayer {
+    lifecycle: SharedLifecycle,
+}
+
+
+#[derive(Clone)]
+pub struct InFlightService<S> {
+    inner: S,
+    lifecycle: SharedLifecycle,
+}
+
+
+/// Decrements the in-flight counts when the request completes or is dropped
+struct InFlightGuard {
+    lifecycle: SharedLifecycle,
+    upload: bool,
+}
+
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the server has begun shutting down and should receive no new
+    /// traffic
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.drain_started.notify_waiters();
+    }
+
+    /// Resolves once the server begins shutting down, for responses such as
+    /// event streams that would otherwise hold their connection open
+    pub async fn until_draining(&self) {
+        let started = self.drain_started.notified();
+
+        if self.is_draining() {
+            return;
+        }
+
+        started.await
+    }
+
+    /// Requests currently being handled
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// State uploads currently being handled; included in `in_flight`
+    pub fn in_flight_uploads(&self) -> usize {
+        self.in_flight_uploads.load(Ordering::SeqCst)
+    }
+
+    fn track(self: &Arc<Self>, upload: bool) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        if upload {
+            self.in_flight_uploads.fetch_add(1, Ordering::SeqCst);
+        }
+
+        InFlightGuard {
+            lifecycle: self.clone(),
+            upload,
+        }
+    }
+}
+
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.lifecycle.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        if self.upload {
+            self.lifecycle.in_flight_uploads.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+
+impl InFlightLayer {
+    pub fn new(lifecycle: SharedLifecycle) -> Self {
+        Self {
+            lifecycle,
+        }
+    }
+}
+
+
+impl<S> Layer<S> for InFlightLayer {
+    type Service = InFlightService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        InFlightService {
+            inner,
+            lifecycle: self.lifecycle.clone(),
+        }
+    }
+}
+
+
+impl<S, B> Service<Request<B>> for InFlightService<S>
+where
+    S: Service<Request<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let upload = request.method() == Method::POST && request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str() == STATE_ROUTE)
+            .unwrap_or(false);
+        let guard = self.lifecycle.track(upload);
+        let future = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = future.await;
+
+            drop(guard);
+
+            response
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Lifecycle;
+
+    #[test]
+    fn test_in_flight() {
+        let lifecycle = Arc::new(Lifecycle::new());
+
+        let request = lifecycle.track(false);
+        let upload = lifecycle.track(true);
+
+        assert_eq!(lifecycle.in_flight(), 2);
+        assert_eq!(lifecycle.in_flight_uploads(), 1);
+
+        drop(upload);
+
+        assert_eq!(lifecycle.in_flight(), 1);
+        assert_eq!(lifecycle.in_flight_uploads(), 0);
+
+        drop(request);
+
+        assert_eq!(lifecycle.in_flight(), 0);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    admission::{
+        Admission,
+        Rejection,
+    },
+    models::state::State,
+    storage::{
+        SharedStorage,
+        StorageError,
+    },
+};
+
+
+/// State file of the local backend's default workspace
+const STATE_FILE: &str = "terraform.tfstate";
+
+/// Directory holding one `<workspace>/terraform.tfstate` per named workspace
+const WORKSPACES_DIR: &str = "terraform.tfstate.d";
+
+/// Maps a state file to a workspace id unless `--id-template` says otherwise
+pub const DEFAULT_ID_TEMPLATE: &str = "{dir}-{workspace}";
+
+
+/// A state file found on disk and what importing it would do
+#[derive(Debug)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub id: String,
+    pub action: Action,
+    state: Option<String>,
+    /// The state as admission read it, for the inventory once it is written
+    admitted: Option<State>,
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Create,
+    Update { from_serial: i64, to_serial: i64 },
+    UpToDate,
+    Conflict(String),
+    Invalid(String),
+}
+
+
+#[derive(Debug)]
+pub enum LocalImportError {
+    Io(PathBuf, std::io::Error),
+    Storage(StorageError),
+}
+
+
+/// The fields of a state file that identify it and order its versions
+#[derive(Debug, Deserialize)]
+struct StateHeader {
+    version: u64,
+    lineage: String,
+    serial: i64,
+}
+
+
+impl Action {
+    /// Whether applying the plan writes this candidate
+    pub fn writes(&self) -> bool {
+        matches!(self, Self::Create | Self::Update { .. })
+    }
+}
+
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Create => write!(f, "create"),
+            Self::Update { from_serial, to_serial } => write!(f, "update serial {} -> {}", from_serial, to_serial),
+            Self::UpToDate => write!(f, "up to date"),
+            Self::Conflict(reason) => write!(f, "conflict: {}", reason),
+            Self::Invalid(reason) => write!(f, "invalid: {}", reason),
+        }
+    }
+}
+
+
+impl fmt::Display for LocalImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Failed to read {}: {}", path.display(), e),
+            Self::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+
+impl std::error::Error for LocalImportError {}
+
+
+impl From<StorageError> for LocalImportError {
+    fn from(e: StorageError) -> Self {
+        Self::Storage(e)
+    }
+}
+
+
+/// Finds every local backend state under `root` and works out what importing
+/// it would do, without writing anything. Ids come from `template`, in which
+/// `{dir}` is the directory holding the state relative to `root` with `/`
+/// replaced by `-` (the name of `root` itself for states directly in it) and
+/// `{workspace}` is the workspace name, `default` for `terraform.tfstate`.
+/// States that would be written must pass `admission`.
+pub async fn plan(storage: &SharedStorage, root: &Path, template: &str, admission: &Admission<'_>) -> Result<Vec<Candidate>, LocalImportError> {
+    let mut found = Vec::new();
+
+    discover(root, root, &mut found)?;
+
+    let root_name = root
+        .canonicalize()
+        .map_err(|e| LocalImportError::Io(root.to_path_buf(), e))?
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "root".to_string());
+
+    let mut candidates = Vec::new();
+
+    for (path, dir, workspace) in found {
+        let dir = if dir.is_empty() { root_name.clone() } else { dir };
+        let id = template
+            .replace("{dir}", &dir)
+            .replace("{workspace}", &workspace);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| LocalImportError::Io(path.clone(), e))?;
+        let (action, state) = match header(&contents) {
+            Ok((header, state)) => (compare(storage, &id, &header, &state).await?, Some(state.to_string())),
+            Err(reason) => (Action::Invalid(reason), None),
+        };
+
+        candidates.push(Candidate {
+            path,
+            id,
+            action,
+            state,
+            admitted: None,
+        });
+    }
+
+    // two files mapping to one id would overwrite each other
+    let mut sources: HashMap<String, Vec<PathBuf>> = HashMap::new();
+
+    for candidate in candidates.iter() {
+        sources.entry(candidate.id.clone())
+            .or_default()
+            .push(candidate.path.clone());
+    }
+
+    for candidate in candidates.iter_mut() {
+        let paths = &sources[&candidate.id];
+
+        if paths.len() > 1 {
+            let others: Vec<_> = paths
+                .iter()
+                .filter(|path| **path != candidate.path)
+                .map(|path| path.display().to_string())
+                .collect();
+
+            candidate.action = Action::Conflict(format!("{} also maps to this id", others.join(", ")));
+        }
+    }
+
+    for candidate in candidates.iter_mut().filter(|candidate| candidate.action.writes()) {
+        let document: Value = match &candidate.state {
+            Some(state) => serde_json::from_str(state).unwrap_or(Value::Null),
+            None => continue,
+        };
+
+        match admission.check(storage, &candidate.id, &document).await {
+            Ok(state) => candidate.admitted = Some(state),
+            Err(Rejection::Storage(e)) => return Err(e.into()),
+            Err(rejection) => candidate.action = Action::Invalid(rejection.to_string()),
+        }
+    }
+
+    Ok(candidates)
+}
+
+
+/// Writes the candidates the plan would create or update and indexes them in
+/// `admission`'s inventory, returning how many were written
+pub async fn apply(storage: &SharedStorage, candidates: &[Candidate], admission: &Admission<'_>) -> Result<usize, StorageError> {
+    let mut written = 0;
+
+    for candidate in candidates.iter().filter(|candidate| candidate.action.writes()) {
+        if let (Some(state), Some(admitted)) = (&candidate.state, &candidate.admitted) {
+            storage.create_or_replace(&candidate.id, state)
+                .await?;
+            admission.inventory.update(&candidate.id, admitted);
+
+            written += 1;
+        }
+    }
+
+    Ok(written)
+}
+
+
+/// Collects `(path, dir, workspace)` for the states under `dir`, skipping
+/// hidden directories such as `.terraform`, whose `terraform.tfstate` holds
+/// backend settings rather than state
+fn discover(root: &Path, dir: &Path, found: &mut Vec<(PathBuf, String, String)>) -> Result<(), LocalImportError> {
+    let io_error = |e| LocalImportError::Io(dir.to_path_buf(), e);
+    let relative = dir
+        .strip_prefix(root)
+        .unwrap_or(dir)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    let default = dir.join(STATE_FILE);
+
+    if default.is_file() {
+        found.push((default, relative.clone(), "default".to_string()));
+    }
+
+    let workspaces = dir.join(WORKSPACES_DIR);
+
+    if workspaces.is_dir() {
+        let mut names: Vec<_> = fs::read_dir(&workspaces)
+            .map_err(io_error)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join(STATE_FILE).is_file())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+
+        names.sort();
+
+        for name in names {
+            found.push((workspaces.join(&name).join(STATE_FILE), relative.clone(), name));
+        }
+    }
+
+    let mut children: Vec<_> = fs::read_dir(dir)
+        .map_err(io_error)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name != WORKSPACES_DIR && !name.to_string_lossy().starts_with('.'))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    children.sort();
+
+    for child in children {
+        discover(root, &child, found)?;
+    }
+
+    Ok(())
+}
+
+
+/// Parses a state's header and returns it with the state in the compact form
+/// the server stores
+fn header(contents: &str) -> Result<(StateHeader, Value), String> {
+    let state: Value = serde_json::from_str(contents)
+        .map_err(|e| format!("not JSON: {}", e))?;
+    let header: StateHeader = serde_json::from_value(state.clone())
+        .map_err(|e| format!("not a terraform state: {}", e))?;
+
+    if !(3..=4).contains(&header.version) {
+        return Err(format!("unsupported state format version {}", header.version));
+    }
+
+    if header.lineage.is_empty() {
+        return Err("state has no lineage".to_string());
+    }
+
+    Ok((header, state))
+}
+
+
+async fn compare(storage: &SharedStorage, id: &str, local: &StateHeader, state: &Value) -> Result<Action, StorageError> {
+    if let Some(lock) = storage.get_lock_by_terraform_id(id).await? {
+        return Ok(Action::Conflict(format!("locked by {}", lock.id)));
+    }
+
+    let stored = match storage.get(id).await? {
+        Some(row) => row,
+        None => return Ok(Action::Create),
+    };
+
+    let (remote, remote_state) = match header(&stored.state) {
+        Ok(parsed) => parsed,
+        Err(reason) => return Ok(Action::Conflict(format!("stored state is unusable: {}", reason))),
+    };
+
+    let action = if remote.lineage != local.lineage {
+        Action::Conflict(format!("lineage {} differs from the stored {}", local.lineage, remote.lineage))
+    } else if remote.serial > local.serial {
+        Action::Conflict(format!("stored serial {} is newer than {}", remote.serial, local.serial))
+    } else if remote.serial == local.serial && remote_state == *state {
+        Action::UpToDate
+    } else if remote.serial == local.serial {
+        Action::Conflict(format!("serial {} differs from the stored state with the same serial", local.serial))
+    } else {
+        Action::Update {
+            from_serial: remote.serial,
+            to_serial: local.serial,
+        }
+    };
+
+    Ok(action)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        sync::Arc,
+    };
+
+    use tokio;
+
+    use super::{
+        Action,
+        DEFAULT_ID_TEMPLATE,
+        apply,
+        plan,
+    };
+    use crate::{
+        admission::Admission,
+        inventory::{
+            DuplicatePolicy,
+            Inventory,
+            VersionFilter,
+        },
+        rules::RulesMode,
+        storage::{
+            MemoryStorage,
+            SharedStorage,
+        },
+    };
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap())
+            .unwrap();
+        fs::write(path, contents)
+            .unwrap();
+    }
+
+    fn state(lineage: &str, serial: i64) -> String {
+        format!("{{\"version\": 4, \"terraform_version\": \"1.0.0\", \"lineage\": \"{}\", \"serial\": {}, \"resources\": []}}", lineage, serial)
+    }
+
+    #[tokio::test]
+    async fn test_plan_apply() {
+        let root = std::env::temp_dir()
+            .join(format!("tf-http-backend-local-{}", std::process::id()))
+            .join("teams");
+
+        fs::remove_dir_all(&root).ok();
+
+        write(&root.join("terraform.tfstate"), &state("root", 1));
+        write(&root.join("network/terraform.tfstate"), &state("network", 3));
+        write(&root.join("network/terraform.tfstate.d/staging/terraform.tfstate"), &state("staging", 5));
+        write(&root.join("network/terraform.tfstate.d/prod/terraform.tfstate"), &state("prod", 2));
+        write(&root.join("network/.terraform/terraform.tfstate"), "{\"version\": 3, \"backend\": {}}");
+        write(&root.join("dns/terraform.tfstate"), "{\"version\": 4}");
+        // readable by the local backend but not a state a POST would accept
+        write(&root.join("legacy/terraform.tfstate"), "{\"version\": 4, \"lineage\": \"legacy\", \"serial\": 1}");
+
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let inventory = Inventory::new();
+        let admission = Admission {
+            rules: &[],
+            rules_mode: RulesMode::Enforce,
+            duplicates: DuplicatePolicy::Reject,
+            inventory: &inventory,
+        };
+
+        storage.create_or_replace("network-staging", &state("staging", 4))
+            .await
+            .unwrap();
+        storage.create_or_replace("network-prod", &state("other", 1))
+            .await
+            .unwrap();
+
+        let candidates = plan(&storage, &root, DEFAULT_ID_TEMPLATE, &admission)
+            .await
+            .unwrap();
+        let actions: Vec<_> = candidates
+            .iter()
+            .map(|candidate| (candidate.id.as_str(), &candidate.action))
+            .collect();
+
+        assert_eq!(actions.len(), 6);
+        assert_eq!(actions[0], ("teams-default", &Action::Create));
+        assert!(matches!(actions[1], ("dns-default", Action::Invalid(_))));
+        assert!(matches!(actions[2], ("legacy-default", Action::Invalid(_))));
+        assert_eq!(actions[3], ("network-default", &Action::Create));
+        assert!(matches!(actions[4], ("network-prod", Action::Conflict(_))));
+        assert_eq!(actions[5], ("network-staging", &Action::Update { from_serial: 4, to_serial: 5 }));
+
+        // planning writes nothing
+        assert!(storage.get("teams-default").await.unwrap().is_none());
+
+        assert_eq!(apply(&storage, &candidates, &admission).await.unwrap(), 3);
+        assert_eq!(inventory.versions(&VersionFilter::default()).len(), 3);
+
+        let candidates = plan(&storage, &root, DEFAULT_ID_TEMPLATE, &admission)
+            .await
+            .unwrap();
+
+        assert!(candidates.iter().all(|candidate| !candidate.action.writes()));
+        assert_eq!(candidates[0].action, Action::UpToDate);
+
+        // every default workspace maps to the same id
+        let candidates = plan(&storage, &root, "{workspace}", &admission)
+            .await
+            .unwrap();
+
+        assert!(candidates
+            .iter()
+            .filter(|candidate| candidate.id == "default")
+            .all(|candidate| matches!(candidate.action, Action::Conflict(_))));
+    }
+}
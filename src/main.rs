@@ -1,60 +1,324 @@
 use std::{
+    collections::HashMap,
+    fs,
     net::SocketAddr,
+    path::PathBuf,
+    process,
     sync::Arc,
+    time::{
+        Duration,
+        SystemTime,
+    },
 };
 
-use axum;
-use envconfig::Envconfig;
+use clap::Parser;
+use tokio::{
+    signal::unix::{
+        SignalKind,
+        signal,
+    },
+    sync::oneshot,
+};
+use tracing_subscriber::{
+    EnvFilter,
+    reload::Handle,
+};
+
+use terraform_http_backend_rs::{
+    admin,
+    api::Api,
+    backup,
+    cli::Cli,
+    config::{
+        self,
+        ConfigurationHandle,
+        Settings,
+        SharedConfiguration,
+    },
+    database,
+    inventory,
+    storage::{
+        self,
+        MemoryStorage,
+        SharedStorage,
+    },
+    webhooks,
+};
 
-use crate::api::Api;
 
-mod api;
-mod config;
-mod database;
-mod db;
-mod error;
-mod extractors;
-mod models;
-mod routes;
+/// How often watched config files are checked for changes
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+
+type LogFilterHandle = Handle<EnvFilter, tracing_subscriber::fmt::Formatter>;
 
 
 #[tokio::main]
 async fn main() {
-    let config = Arc::new(
-        config::Configuration::init_from_env()
-            .unwrap()
-    );
+    let cli = Cli::parse();
+    let settings = match load_settings(&cli) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+
+    if cli.check_config {
+        return check_config(&settings);
+    }
 
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(
-            config
+            settings
+                .config
                 .log_level
                 .to_string()
         )
-        .init();
+        .with_filter_reloading();
+    let log_filter = subscriber.reload_handle();
 
-    let config = Arc::new(
-        config::Configuration::init_from_env()
-            .unwrap()
-    );
+    subscriber.init();
+
+    if let Some(command) = cli.command {
+        if let Err(e) = admin::run(command, &settings.config).await {
+            eprintln!("{:#}", e);
+            process::exit(1);
+        }
+
+        return;
+    }
+
+    let handle: SharedConfiguration = Arc::new(ConfigurationHandle::new(settings));
+    let settings = handle.current();
+    let config = &settings.config;
+
+    tokio::spawn(watch_config(cli, handle.clone(), log_filter));
+
+    let storage: SharedStorage = if config.database_uri == storage::MEMORY_URI {
+        tracing::warn!("Using in-memory storage; state will be lost when the server stops");
+
+        Arc::new(
+            MemoryStorage::new()
+                .with_history(config.state_history, config.state_history_retention),
+        )
+    } else {
+        let database = database::get_db_pool(config)
+            .await
+            .expect("Failed to setup database connection pool!");
 
-    let database = database::get_db_pool(&config)
-        .await
-        .expect("Failed to setup database connection pool!");
+        // attempt to setup migrations as part of application startup
+        database::MIGRATE.run(&database)
+            .await
+            .expect("Failed to run database migrations!");
 
-    // attempt to setup migrations as part of application startup
-    database::MIGRATE.run(&database)
-        .await
-        .expect("Failed to run database migrations!");
+        if let (Some(dir), true) = (&config.backup_dir, config.backup_interval > 0) {
+            tokio::spawn(backup::schedule(
+                database.clone(),
+                dir.into(),
+                Duration::from_secs(config.backup_interval),
+                config.backup_retain,
+            ));
+        }
+
+        storage::from_config(config, database)
+            .await
+            .expect("Failed to setup storage backend!")
+    };
 
     let socket = SocketAddr::from((config.http_bind_address, config.http_port));
-    let api: axum::Router = Api::new(
-        config.clone(),
-        database.clone()
-    ).into();
+    let api = Api::new(
+        handle,
+        storage.clone(),
+    );
+    let lifecycle = api.lifecycle();
+    let events = api.events();
+
+    // indexed before serving so a write cannot be overtaken by an older state
+    match api.inventory().rebuild(&storage).await {
+        Ok(count) => tracing::info!("Indexed the resources of {} states", count),
+        Err(e) => tracing::error!("Failed to index stored states; searches will only find states written from now on: {}", e),
+    }
+
+    tokio::spawn(inventory::keep_fresh(api.inventory(), storage.clone()));
 
-    axum::Server::bind(&socket)
+    match storage.pool() {
+        Some(pool) => {
+            tokio::spawn(webhooks::deliver(pool.clone(), events.clone()));
+            tokio::spawn(webhooks::purge_given_up(pool.clone()));
+        },
+        None if !settings.webhooks.is_empty() => {
+            tracing::warn!("Webhooks need a database for their outbox and will not be sent with in-memory storage");
+        },
+        None => (),
+    }
+
+    if config.stale_lock_age > 0 {
+        tokio::spawn(webhooks::watch_locks(storage.clone(), events, Duration::from_secs(config.stale_lock_age)));
+    }
+
+    let api: axum::Router = api.into();
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    let server = axum::Server::bind(&socket)
         .serve(api.into_make_service())
-        .await
-        .unwrap()
+        .with_graceful_shutdown(async {
+            stopped.await.ok();
+        });
+
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result.unwrap(),
+        _ = shutdown_signal() => {
+            lifecycle.start_draining();
+
+            tracing::info!(
+                "Shutting down; draining {} in-flight requests ({} state uploads)",
+                lifecycle.in_flight(),
+                lifecycle.in_flight_uploads(),
+            );
+
+            // stops accepting connections and waits for open ones to finish
+            stop.send(()).ok();
+
+            let timeout = Duration::from_secs(config.shutdown_timeout);
+
+            match tokio::time::timeout(timeout, &mut server).await {
+                Ok(result) => {
+                    result.unwrap();
+
+                    tracing::info!("Drained all in-flight requests");
+                },
+                Err(_) => {
+                    tracing::warn!(
+                        "Shutdown timeout of {}s elapsed with {} requests ({} state uploads) still in flight",
+                        config.shutdown_timeout,
+                        lifecycle.in_flight(),
+                        lifecycle.in_flight_uploads(),
+                    );
+                },
+            }
+        },
+    }
+
+    if let Some(pool) = storage.pool() {
+        pool.close()
+            .await;
+
+        tracing::info!("Closed database connection pool");
+    }
+}
+
+
+/// Resolves on the first SIGTERM or SIGINT
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler!");
+
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
+
+
+fn load_settings(cli: &Cli) -> Result<Settings, config::ConfigError> {
+    config::Configuration::load(cli.config.as_deref(), cli.flags.values())
+        .and_then(Settings::new)
+}
+
+
+/// Swaps the `Settings` behind `handle` on SIGHUP or when a watched file
+/// changes, and applies the new log level. Settings that fail to load are
+/// rejected and the current ones kept.
+async fn watch_config(cli: Cli, handle: SharedConfiguration, log_filter: LogFilterHandle) {
+    let mut hangup = signal(SignalKind::hangup())
+        .expect("Failed to install SIGHUP handler!");
+    let mut interval = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+    let mut modified = watched_files(&cli, &handle);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                tracing::info!("Received SIGHUP; reloading configuration");
+            },
+            _ = interval.tick() => {
+                let current = watched_files(&cli, &handle);
+
+                if current == modified {
+                    continue;
+                }
+
+                modified = current;
+                tracing::info!("Configuration files changed; reloading configuration");
+            },
+        }
+
+        let settings = match load_settings(&cli) {
+            Ok(settings) => settings,
+            Err(e) => {
+                tracing::error!("Rejected new configuration, keeping the current one: {}", e);
+                continue;
+            },
+        };
+
+        for variable in handle.current().config.restart_required(&settings.config) {
+            tracing::warn!("{} changed but is only read at startup; restart the server to apply it", variable);
+        }
+
+        let log_level = settings
+            .config
+            .log_level
+            .to_string();
+
+        if let Err(e) = log_filter.reload(EnvFilter::new(&log_level)) {
+            tracing::error!("Failed to apply log level {}: {}", log_level, e);
+        }
+
+        handle.replace(settings);
+        modified = watched_files(&cli, &handle);
+
+        tracing::info!("Reloaded configuration");
+    }
+}
+
+
+/// Modification times of the config, auth, webhooks, rules and encryption
+/// keys files, and of the files `*_FILE` variables read secrets from
+fn watched_files(cli: &Cli, handle: &SharedConfiguration) -> HashMap<PathBuf, Option<SystemTime>> {
+    let settings = handle.current();
+    let config = &settings.config;
+    let files = [&config.auth_file, &config.webhooks_file, &config.rules_file, &config.encryption_keys_file];
+
+    cli.config
+        .iter()
+        .cloned()
+        .chain(files.iter().filter_map(|file| file.as_ref().map(PathBuf::from)))
+        .chain(config::secret_files(cli.config.as_deref()))
+        .map(|path| {
+            let modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+
+            (path, modified)
+        })
+        .collect()
+}
+
+
+/// Validates the storage settings, which are otherwise only checked once the
+/// server starts; loading `settings` already parsed the auth, webhooks and
+/// rules files
+fn check_config(settings: &Settings) {
+    if let Err(e) = storage::check_config(&settings.config) {
+        eprintln!("Invalid configuration: {}", e);
+        process::exit(1);
+    }
+
+    println!(
+        "Configuration is valid: {} webhooks, {} rules",
+        settings.webhooks.len(),
+        settings.rules.len(),
+    );
 }
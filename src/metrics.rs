@@ -0,0 +1,229 @@
+use std::{
+    sync::Arc,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Instant,
+};
+
+use axum::extract::MatchedPath;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use http::{
+    Request,
+    Response,
+    StatusCode,
+};
+use prometheus::{
+    Encoder,
+    Gauge,
+    HistogramOpts,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+use tower::{
+    Layer,
+    Service,
+};
+
+use crate::storage::SharedStorage;
+
+
+pub type SharedMetrics = Arc<Metrics>;
+
+
+/// Prometheus metrics for a single `Api`; each instance has its own registry
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub state_read_bytes: IntCounter,
+    pub state_write_bytes: IntCounter,
+    pub lock_acquisitions: IntCounter,
+    pub lock_conflicts: IntCounter,
+    pub locks_held: IntGauge,
+    pub oldest_lock_age: Gauge,
+    pub pool_connections: IntGauge,
+    pub pool_idle_connections: IntGauge,
+    pub auth_failures: IntCounter,
+}
+
+
+/// Records the count and latency of requests by route template and status
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: SharedMetrics,
+}
+
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: SharedMetrics,
+}
+
+
+impl Metrics {
+    pub fn new() -> Self {
+        let labels = &["method", "route", "status"];
+
+        let metrics = Self {
+            registry: Registry::new(),
+            requests: IntCounterVec::new(Opts::new("http_requests_total", "HTTP requests by route and status"), labels)
+                .expect("Invalid metric"),
+            request_duration: HistogramVec::new(HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route and status"), labels)
+                .expect("Invalid metric"),
+            state_read_bytes: IntCounter::new("terraform_state_read_bytes_total", "Bytes of state served")
+                .expect("Invalid metric"),
+            state_write_bytes: IntCounter::new("terraform_state_write_bytes_total", "Bytes of state written")
+                .expect("Invalid metric"),
+            lock_acquisitions: IntCounter::new("terraform_lock_acquisitions_total", "Locks acquired")
+                .expect("Invalid metric"),
+            lock_conflicts: IntCounter::new("terraform_lock_conflicts_total", "Lock requests rejected because the state was locked")
+                .expect("Invalid metric"),
+            locks_held: IntGauge::new("terraform_locks_held", "Locks currently held")
+                .expect("Invalid metric"),
+            oldest_lock_age: Gauge::new("terraform_oldest_lock_age_seconds", "Age of the oldest held lock; 0 if no locks are held")
+                .expect("Invalid metric"),
+            pool_connections: IntGauge::new("sqlite_pool_connections", "Open database connections")
+                .expect("Invalid metric"),
+            pool_idle_connections: IntGauge::new("sqlite_pool_idle_connections", "Idle database connections")
+                .expect("Invalid metric"),
+            auth_failures: IntCounter::new("auth_failures_total", "Requests rejected due to missing or invalid credentials")
+                .expect("Invalid metric"),
+        };
+
+        metrics.register()
+            .expect("Failed to register metrics");
+
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry.register(Box::new(self.requests.clone()))?;
+        self.registry.register(Box::new(self.request_duration.clone()))?;
+        self.registry.register(Box::new(self.state_read_bytes.clone()))?;
+        self.registry.register(Box::new(self.state_write_bytes.clone()))?;
+        self.registry.register(Box::new(self.lock_acquisitions.clone()))?;
+        self.registry.register(Box::new(self.lock_conflicts.clone()))?;
+        self.registry.register(Box::new(self.locks_held.clone()))?;
+        self.registry.register(Box::new(self.oldest_lock_age.clone()))?;
+        self.registry.register(Box::new(self.pool_connections.clone()))?;
+        self.registry.register(Box::new(self.pool_idle_connections.clone()))?;
+        self.registry.register(Box::new(self.auth_failures.clone()))?;
+
+        Ok(())
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: StatusCode, elapsed: f64) {
+        let labels = [method, route, status.as_str()];
+
+        self.requests
+            .with_label_values(&labels)
+            .inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed);
+    }
+
+    /// Refreshes gauges sampled from storage and renders every metric in the
+    /// Prometheus text format
+    pub async fn render(&self, storage: &SharedStorage) -> Result<String, anyhow::Error> {
+        let locks = storage
+            .list_locks()
+            .await?;
+        let now = Utc::now().naive_utc();
+        let oldest = locks
+            .iter()
+            .map(|lock| (now - lock.last_update_ts).num_milliseconds())
+            .max()
+            .unwrap_or(0);
+
+        self.locks_held.set(locks.len() as i64);
+        self.oldest_lock_age.set(oldest.max(0) as f64 / 1000.0);
+
+        if let Some(pool) = storage.pool() {
+            self.pool_connections.set(pool.size() as i64);
+            self.pool_idle_connections.set(pool.num_idle() as i64);
+        }
+
+        let mut buffer = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl MetricsLayer {
+    pub fn new(metrics: SharedMetrics) -> Self {
+        Self {
+            metrics,
+        }
+    }
+}
+
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+
+impl<S, B, ResBody> Service<Request<B>> for MetricsService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // the route template rather than the path keeps label cardinality low
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| "unmatched".to_string());
+        let method = request
+            .method()
+            .to_string();
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let future = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = future.await?;
+
+            metrics.observe_request(&method, &route, response.status(), start.elapsed().as_secs_f64());
+
+            Ok(response)
+        })
+    }
+}
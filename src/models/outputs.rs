@@ -0,0 +1,201 @@
+use serde::Deserialize;
+use serde_json::{
+    Map,
+    Value,
+};
+
+
+/// Shown in place of the value of an output marked sensitive
+pub const SENSITIVE_MASK: &str = "<sensitive>";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// An object of output names to values
+    Json,
+    /// `NAME="value"` lines
+    Dotenv,
+    /// `export NAME='value'` lines for `eval` or `source`
+    Shell,
+    /// `name = value` lines loadable with `-var-file`
+    Tfvars,
+}
+
+
+/// A root module output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub name: String,
+    pub value: Value,
+    pub sensitive: bool,
+}
+
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            _ => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+
+/// The root module outputs of a state document ordered by name; the
+/// `outputs` section of a v4 state, or the root module's of a v3 one
+pub fn root_outputs(state: &Value) -> Map<String, Value> {
+    let outputs = if state.get("version").and_then(Value::as_u64) == Some(3) {
+        state
+            .get("modules")
+            .and_then(|modules| modules.get(0))
+            .and_then(|root| root.get("outputs"))
+    } else {
+        state.get("outputs")
+    };
+
+    outputs
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default()
+}
+
+
+pub fn outputs(state: &Value) -> Vec<Output> {
+    root_outputs(state)
+        .into_iter()
+        .map(|(name, output)| Output {
+            name,
+            value: output.get("value").cloned().unwrap_or(Value::Null),
+            sensitive: output.get("sensitive").and_then(Value::as_bool).unwrap_or(false),
+        })
+        .collect()
+}
+
+
+/// Renders outputs in `format`, replacing sensitive values with
+/// `SENSITIVE_MASK` unless `reveal_sensitive` is set
+pub fn render(outputs: &[Output], format: OutputFormat, reveal_sensitive: bool) -> String {
+    let values = outputs
+        .iter()
+        .map(|output| {
+            let value = if output.sensitive && !reveal_sensitive {
+                Value::String(SENSITIVE_MASK.to_string())
+            } else {
+                output.value.clone()
+            };
+
+            (output.name.as_str(), value)
+        });
+
+    match format {
+        OutputFormat::Json => {
+            let object: Map<String, Value> = values
+                .map(|(name, value)| (name.to_string(), value))
+                .collect();
+
+            Value::Object(object).to_string()
+        },
+        OutputFormat::Dotenv => lines(values, |name, value| format!("{}=\"{}\"", env_name(name), dotenv_escape(&scalar(value)))),
+        OutputFormat::Shell => lines(values, |name, value| format!("export {}='{}'", env_name(name), scalar(value).replace('\'', "'\\''"))),
+        OutputFormat::Tfvars => lines(values, |name, value| format!("{} = {}", name, hcl(value))),
+    }
+}
+
+
+fn lines<'a, I, F>(values: I, line: F) -> String
+where
+    I: Iterator<Item = (&'a str, Value)>,
+    F: Fn(&str, &Value) -> String,
+{
+    values
+        .map(|(name, value)| line(name, &value) + "\n")
+        .collect()
+}
+
+
+/// Strings as they are, anything else as JSON
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        other => other.to_string(),
+    }
+}
+
+
+/// Upper cases an output name and replaces anything not allowed in an
+/// environment variable name with `_`
+fn env_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+
+fn dotenv_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$")
+        .replace('\n', "\\n")
+}
+
+
+/// JSON is valid HCL apart from template sequences in strings, which are
+/// escaped so values are taken literally
+fn hcl(value: &Value) -> String {
+    value
+        .to_string()
+        .replace("${", "$${")
+        .replace("%{", "%%{")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{
+        OutputFormat,
+        outputs,
+        render,
+    };
+
+    #[test]
+    fn test_render() {
+        let state = json!({
+            "version": 4,
+            "outputs": {
+                "vpc_id": {"value": "vpc-123", "type": "string"},
+                "db_password": {"value": "it's $ecret", "type": "string", "sensitive": true},
+                "subnets": {"value": ["a", "b"], "type": ["list", "string"]},
+                "template": {"value": "${var.x}", "type": "string"},
+            },
+        });
+        let outputs = outputs(&state);
+
+        assert_eq!(
+            render(&outputs, OutputFormat::Json, false),
+            "{\"db_password\":\"<sensitive>\",\"subnets\":[\"a\",\"b\"],\"template\":\"${var.x}\",\"vpc_id\":\"vpc-123\"}",
+        );
+        assert_eq!(
+            render(&outputs, OutputFormat::Dotenv, true),
+            "DB_PASSWORD=\"it's \\$ecret\"\nSUBNETS=\"[\\\"a\\\",\\\"b\\\"]\"\nTEMPLATE=\"\\${var.x}\"\nVPC_ID=\"vpc-123\"\n",
+        );
+        assert_eq!(
+            render(&outputs, OutputFormat::Shell, true),
+            "export DB_PASSWORD='it'\\''s $ecret'\nexport SUBNETS='[\"a\",\"b\"]'\nexport TEMPLATE='${var.x}'\nexport VPC_ID='vpc-123'\n",
+        );
+        assert_eq!(
+            render(&outputs, OutputFormat::Tfvars, false),
+            "db_password = \"<sensitive>\"\nsubnets = [\"a\",\"b\"]\ntemplate = \"$${var.x}\"\nvpc_id = \"vpc-123\"\n",
+        );
+    }
+}
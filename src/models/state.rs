@@ -0,0 +1,595 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::{
+    Map,
+    Value,
+    json,
+};
+
+use crate::models::outputs::{
+    SENSITIVE_MASK,
+    root_outputs,
+};
+
+
+/// The only state format terraform has written since 0.12
+pub const STATE_VERSION: u64 = 4;
+
+/// Top level fields of a state document that say nothing about resources
+const HEADER_FIELDS: &[&str] = &["version", "terraform_version", "serial", "lineage"];
+
+
+/// A version 4 state document. Fields terraform may add later are ignored
+/// rather than rejected, so the stored document is always the one posted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub version: u64,
+    pub terraform_version: String,
+    pub serial: u64,
+    pub lineage: String,
+    #[serde(default)]
+    pub outputs: BTreeMap<String, OutputValue>,
+    #[serde(default)]
+    pub resources: Vec<Resource>,
+    /// Results of `check` blocks and conditions, written since terraform 1.3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_results: Option<Vec<CheckResult>>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputValue {
+    pub value: Value,
+    /// A terraform type expression such as `"string"` or `["list", "string"]`
+    #[serde(rename = "type", default)]
+    pub value_type: Value,
+    #[serde(default)]
+    pub sensitive: bool,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Resource {
+    /// The module address, absent for resources of the root module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    pub mode: ResourceMode,
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub name: String,
+    /// `"list"` for `count` and `"map"` for `for_each`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub each: Option<String>,
+    pub provider: String,
+    pub instances: Vec<Instance>,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResourceMode {
+    Managed,
+    Data,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Instance {
+    /// The `count` index or `for_each` key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_key: Option<Value>,
+    pub schema_version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<Value>,
+    /// Attributes of states upgraded from before 0.12, flattened to strings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes_flat: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    pub sensitive_attributes: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<String>,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub create_before_destroy: bool,
+    /// `"tainted"` for instances that must be replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Set on objects kept while their replacement is created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deposed: Option<String>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub object_kind: String,
+    pub config_addr: String,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub objects: Option<Vec<Value>>,
+}
+
+
+/// Why a document is not a state, naming the offending field
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidState {
+    pub path: String,
+    pub message: String,
+}
+
+
+impl State {
+    /// Checks that a document is a state terraform could have written,
+    /// beyond the shape its fields have to take
+    pub fn from_value(value: &Value) -> Result<Self, InvalidState> {
+        if !value.is_object() {
+            return Err(InvalidState::new(".", "expected a state object"));
+        }
+
+        // the version decides how the rest is read, so it is checked first
+        match value.get("version").map(Value::as_u64) {
+            None => return Err(InvalidState::new("version", "missing field `version`")),
+            Some(Some(STATE_VERSION)) => (),
+            Some(Some(version)) => return Err(InvalidState::new("version", &format!("unsupported state version {}, expected {}", version, STATE_VERSION))),
+            Some(None) => return Err(InvalidState::new("version", "expected an unsigned integer")),
+        }
+
+        let state: Self = serde_path_to_error::deserialize(value)
+            .map_err(|e| InvalidState::new(&e.path().to_string(), &e.inner().to_string()))?;
+
+        if state.lineage.is_empty() {
+            return Err(InvalidState::new("lineage", "must not be empty"));
+        }
+
+        for (index, resource) in state.resources.iter().enumerate() {
+            if resource.resource_type.is_empty() || resource.name.is_empty() {
+                return Err(InvalidState::new(&format!("resources[{}]", index), "type and name must not be empty"));
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+
+impl Resource {
+    /// The address of one of the resource's instances as terraform prints it,
+    /// e.g. `module.network.aws_subnet.private["a"]`
+    pub fn address(&self, instance: &Instance) -> String {
+        let mut address = String::new();
+
+        if let Some(module) = &self.module {
+            address.push_str(module);
+            address.push('.');
+        }
+
+        if self.mode == ResourceMode::Data {
+            address.push_str("data.");
+        }
+
+        address.push_str(&self.resource_type);
+        address.push('.');
+        address.push_str(&self.name);
+
+        match &instance.index_key {
+            Some(Value::String(key)) => address.push_str(&format!("[{}]", Value::String(key.clone()))),
+            Some(key) => address.push_str(&format!("[{}]", key)),
+            None => (),
+        }
+
+        address
+    }
+
+    /// The provider's source address without the `provider[...]` wrapping or
+    /// an alias, e.g. `registry.terraform.io/hashicorp/aws`
+    pub fn provider_source(&self) -> &str {
+        self.provider
+            .strip_prefix("provider[\"")
+            .and_then(|rest| rest.split_once("\"]"))
+            .map(|(source, _)| source)
+            .unwrap_or(&self.provider)
+    }
+}
+
+
+impl InvalidState {
+    fn new(path: &str, message: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+
+impl fmt::Display for InvalidState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path == "." {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+
+impl std::error::Error for InvalidState {}
+
+
+/// A copy of a state document keeping its header and root module outputs and
+/// nothing else, which `terraform_remote_state` still accepts
+pub fn outputs_only(state: &Value) -> Value {
+    let mut document = Map::new();
+    let outputs = root_outputs(state);
+
+    for field in HEADER_FIELDS {
+        if let Some(value) = state.get(field) {
+            document.insert(field.to_string(), value.clone());
+        }
+    }
+
+    // before 0.12 outputs belonged to modules, the first being the root
+    if state.get("version").and_then(Value::as_u64) == Some(3) {
+        document.insert("modules".to_string(), json!([{"path": ["root"], "outputs": outputs, "resources": {}}]));
+    } else {
+        document.insert("outputs".to_string(), Value::Object(outputs));
+        document.insert("resources".to_string(), json!([]));
+    }
+
+    Value::Object(document)
+}
+
+
+/// A copy of a state document for principals who may only view it, with the
+/// values of sensitive outputs and of the attributes each instance lists in
+/// `sensitive_attributes` replaced by a mask. Attributes whose sensitive paths
+/// cannot be followed are masked as a whole. Flat attributes, whether in
+/// `attributes_flat` or a pre-0.12 state, carry no sensitivity and are all
+/// masked, and provider `private` data is removed.
+pub fn redacted(state: &Value) -> Value {
+    let mut document = state.clone();
+
+    if let Some(outputs) = document.get_mut("outputs") {
+        mask_sensitive_outputs(outputs);
+    }
+
+    // before 0.12 outputs and resources belonged to modules
+    if let Some(modules) = document.get_mut("modules").and_then(Value::as_array_mut) {
+        for module in modules.iter_mut() {
+            if let Some(outputs) = module.get_mut("outputs") {
+                mask_sensitive_outputs(outputs);
+            }
+
+            let resources = module
+                .get_mut("resources")
+                .and_then(Value::as_object_mut)
+                .into_iter()
+                .flat_map(|resources| resources.values_mut())
+                .filter_map(Value::as_object_mut);
+
+            for resource in resources {
+                for (key, value) in resource.iter_mut() {
+                    let instances = match (key.as_str(), value) {
+                        ("primary", instance) => vec![instance],
+                        ("deposed", Value::Array(instances)) => instances.iter_mut().collect(),
+                        _ => continue,
+                    };
+
+                    for instance in instances {
+                        if let Some(attributes) = instance.get_mut("attributes") {
+                            mask_all(attributes);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+
+    let resources = document
+        .get_mut("resources")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten();
+
+    for resource in resources {
+        let instances = resource
+            .get_mut("instances")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten();
+
+        for instance in instances {
+            if let Some(object) = instance.as_object_mut() {
+                object.remove("private");
+            }
+
+            if let Some(attributes) = instance.get_mut("attributes_flat") {
+                mask_all(attributes);
+            }
+
+            let paths = match instance.get("sensitive_attributes").and_then(Value::as_array) {
+                Some(paths) if !paths.is_empty() => paths.clone(),
+                _ => continue,
+            };
+
+            if let Some(attributes) = instance.get_mut("attributes") {
+                if !paths.iter().all(|path| mask_path(attributes, path)) {
+                    *attributes = Value::String(SENSITIVE_MASK.to_string());
+                }
+            }
+        }
+    }
+
+    document
+}
+
+
+/// Masks every value of a map of flat attributes
+fn mask_all(attributes: &mut Value) {
+    let values = attributes
+        .as_object_mut()
+        .into_iter()
+        .flat_map(|attributes| attributes.values_mut());
+
+    for value in values {
+        *value = Value::String(SENSITIVE_MASK.to_string());
+    }
+}
+
+
+fn mask_sensitive_outputs(outputs: &mut Value) {
+    let outputs = outputs
+        .as_object_mut()
+        .into_iter()
+        .flat_map(|outputs| outputs.values_mut());
+
+    for output in outputs {
+        if output.get("sensitive").and_then(Value::as_bool).unwrap_or(false) {
+            output["value"] = Value::String(SENSITIVE_MASK.to_string());
+        }
+    }
+}
+
+
+/// Masks the value a sensitive attribute path such as
+/// `[{"type": "get_attr", "value": "password"}]` leads to, returning false if
+/// the path is not in that form. A path to a missing value has nothing to mask.
+fn mask_path(attributes: &mut Value, path: &Value) -> bool {
+    let steps = match path.as_array() {
+        Some(steps) if !steps.is_empty() => steps,
+        _ => return false,
+    };
+
+    let mut target = attributes;
+
+    for step in steps {
+        let next = match (step.get("type").and_then(Value::as_str), step.get("value")) {
+            (Some("get_attr"), Some(Value::String(name))) => target.get_mut(name.as_str()),
+            (Some("index"), Some(key)) => match key.get("value") {
+                Some(Value::Number(index)) => index
+                    .as_u64()
+                    .and_then(move |index| target.get_mut(index as usize)),
+                Some(Value::String(key)) => target.get_mut(key.as_str()),
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        target = match next {
+            Some(next) => next,
+            None => return true,
+        };
+    }
+
+    *target = Value::String(SENSITIVE_MASK.to_string());
+
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{
+        ResourceMode,
+        State,
+        outputs_only,
+        redacted,
+    };
+
+    #[test]
+    fn test_from_value() {
+        let document = json!({
+            "version": 4,
+            "terraform_version": "1.3.0",
+            "serial": 3,
+            "lineage": "abcd",
+            "outputs": {"vpc_id": {"value": "vpc-123", "type": "string"}},
+            "resources": [{
+                "module": "module.network",
+                "mode": "data",
+                "type": "aws_vpc",
+                "name": "main",
+                "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+                "instances": [{"schema_version": 1, "attributes": {"id": "vpc-123"}, "sensitive_attributes": []}],
+            }],
+            "check_results": null,
+            "added_in_a_later_release": true,
+        });
+
+        let state = State::from_value(&document)
+            .unwrap();
+
+        assert_eq!(state.resources[0].mode, ResourceMode::Data);
+        assert_eq!(state.resources[0].instances[0].attributes, Some(json!({"id": "vpc-123"})));
+        assert_eq!(state.outputs["vpc_id"].value, "vpc-123");
+        assert_eq!(state.resources[0].address(&state.resources[0].instances[0]), "module.network.data.aws_vpc.main");
+        assert_eq!(state.resources[0].provider_source(), "registry.terraform.io/hashicorp/aws");
+
+        let cases = [
+            (json!([]), "expected a state object"),
+            (json!({"version": "4"}), "version: expected an unsigned integer"),
+            (json!({"version": 4, "terraform_version": "1.3.0", "serial": -1, "lineage": "abcd"}), "serial: invalid value: integer `-1`, expected u64"),
+            (json!({"version": 4, "terraform_version": "1.3.0", "serial": 1, "lineage": ""}), "lineage: must not be empty"),
+            (json!({"version": 4, "terraform_version": "1.3.0", "serial": 1, "lineage": "abcd", "outputs": {"x": {}}}), "outputs.x: missing field `value`"),
+        ];
+
+        for (document, message) in cases {
+            assert_eq!(State::from_value(&document).unwrap_err().to_string(), message);
+        }
+
+        let mut invalid = document.clone();
+
+        invalid["resources"][0]["mode"] = json!("imported");
+
+        assert!(State::from_value(&invalid).unwrap_err().to_string().starts_with("resources[0].mode: unknown variant `imported`"));
+    }
+
+    #[test]
+    fn test_outputs_only() {
+        let state = json!({
+            "version": 4,
+            "terraform_version": "1.0.11",
+            "serial": 7,
+            "lineage": "abcd",
+            "outputs": {
+                "vpc_id": {"value": "vpc-123", "type": "string"},
+                "db_password": {"value": "hunter2", "type": "string", "sensitive": true},
+            },
+            "resources": [{"mode": "managed", "type": "aws_db_instance", "instances": [{"attributes": {"password": "hunter2"}}]}],
+            "check_results": null,
+        });
+
+        // terraform_remote_state consumers read sensitive outputs too
+        assert_eq!(outputs_only(&state), json!({
+            "version": 4,
+            "terraform_version": "1.0.11",
+            "serial": 7,
+            "lineage": "abcd",
+            "outputs": {
+                "vpc_id": {"value": "vpc-123", "type": "string"},
+                "db_password": {"value": "hunter2", "type": "string", "sensitive": true},
+            },
+            "resources": [],
+        }));
+
+        let legacy = json!({
+            "version": 3,
+            "serial": 2,
+            "lineage": "abcd",
+            "modules": [
+                {"path": ["root"], "outputs": {"vpc_id": {"value": "vpc-123"}}, "resources": {"aws_vpc.main": {}}},
+                {"path": ["root", "child"], "outputs": {}, "resources": {"aws_instance.web": {}}},
+            ],
+        });
+
+        assert_eq!(outputs_only(&legacy)["modules"], json!([{"path": ["root"], "outputs": {"vpc_id": {"value": "vpc-123"}}, "resources": {}}]));
+    }
+
+    #[test]
+    fn test_redacted() {
+        let state = json!({
+            "version": 4,
+            "terraform_version": "1.0.11",
+            "serial": 7,
+            "lineage": "abcd",
+            "outputs": {
+                "endpoint": {"value": "db.internal", "type": "string"},
+                "password": {"value": "hunter2", "type": "string", "sensitive": true},
+            },
+            "resources": [
+                {
+                    "mode": "managed",
+                    "type": "aws_db_instance",
+                    "name": "main",
+                    "provider": "aws",
+                    "instances": [{
+                        "schema_version": 1,
+                        "attributes": {"endpoint": "db.internal", "password": "hunter2", "users": [{"name": "app", "password": "s3cret"}]},
+                        "sensitive_attributes": [
+                            [{"type": "get_attr", "value": "password"}],
+                            [{"type": "get_attr", "value": "users"}, {"type": "index", "value": {"value": 0, "type": "number"}}, {"type": "get_attr", "value": "password"}],
+                            [{"type": "get_attr", "value": "removed"}],
+                        ],
+                    }],
+                },
+                {
+                    "mode": "managed",
+                    "type": "aws_secret",
+                    "name": "api",
+                    "provider": "aws",
+                    "instances": [{"schema_version": 1, "attributes": {"value": "xyz"}, "sensitive_attributes": [{"path": "value"}]}],
+                },
+                {
+                    "mode": "managed",
+                    "type": "aws_iam_access_key",
+                    "name": "legacy",
+                    "provider": "aws",
+                    "instances": [{
+                        "schema_version": 0,
+                        "attributes_flat": {"id": "AKIAEXAMPLE", "secret": "wJalrXUtnFEMI"},
+                        "private": "eyJzZWNyZXQiOiJ3SmFsclhVdG5GRU1JIn0=",
+                    }],
+                },
+            ],
+        });
+
+        let view = redacted(&state);
+
+        assert_eq!(view["outputs"]["endpoint"]["value"], "db.internal");
+        assert_eq!(view["outputs"]["password"]["value"], "<sensitive>");
+        assert_eq!(view["resources"][0]["instances"][0]["attributes"], json!({
+            "endpoint": "db.internal",
+            "password": "<sensitive>",
+            "users": [{"name": "app", "password": "<sensitive>"}],
+        }));
+
+        // an unknown path format masks every attribute
+        assert_eq!(view["resources"][1]["instances"][0]["attributes"], "<sensitive>");
+        assert_eq!(view["lineage"], "abcd");
+
+        // flat attributes have no sensitivity and private data is opaque
+        let legacy = &view["resources"][2]["instances"][0];
+
+        assert_eq!(legacy["attributes_flat"], json!({"id": "<sensitive>", "secret": "<sensitive>"}));
+        assert!(legacy.get("private").is_none());
+        assert_eq!(legacy["schema_version"], 0);
+    }
+
+    #[test]
+    fn test_redacted_legacy() {
+        let state = json!({
+            "version": 3,
+            "serial": 2,
+            "modules": [{
+                "path": ["root"],
+                "outputs": {"token": {"value": "s3cret", "type": "string", "sensitive": true}},
+                "resources": {
+                    "aws_db_instance.main": {
+                        "type": "aws_db_instance",
+                        "primary": {"id": "main", "attributes": {"id": "main", "password": "hunter2"}},
+                        "deposed": [{"id": "old", "attributes": {"password": "hunter1"}}],
+                    },
+                },
+            }],
+        });
+
+        let view = redacted(&state);
+        let resource = &view["modules"][0]["resources"]["aws_db_instance.main"];
+
+        assert_eq!(view["modules"][0]["outputs"]["token"]["value"], "<sensitive>");
+        assert_eq!(resource["primary"]["attributes"], json!({"id": "<sensitive>", "password": "<sensitive>"}));
+        assert_eq!(resource["deposed"][0]["attributes"]["password"], "<sensitive>");
+        assert_eq!(resource["primary"]["id"], "main");
+    }
+}
@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use axum::{
+    extract::Extension,
+    response::IntoResponse,
+    Json,
+};
+use axum_debug::debug_handler;
+use serde_json::json;
+
+use crate::{
+    auth::Permission,
+    backup,
+    config::SharedConfiguration,
+    error::{
+        HttpError,
+        Loggable,
+    },
+    extractors::LoginExtractor,
+    storage::SharedStorage,
+};
+
+
+/// Workspace pattern an admin must hold `admin` on for server-wide endpoints
+pub const ALL_WORKSPACES: &str = "*";
+
+
+pub struct AdminBackupRoute;
+
+
+impl AdminBackupRoute {
+    /// Writes a backup into `BACKUP_DIR` while the server keeps serving
+    #[debug_handler]
+    pub async fn post(
+        LoginExtractor(principal): LoginExtractor,
+        Extension(config): Extension<SharedConfiguration>,
+        Extension(storage): Extension<SharedStorage>,
+    ) -> Result<impl IntoResponse, HttpError> {
+        principal.authorize(ALL_WORKSPACES, Permission::Admin)?;
+
+        let settings = config.current();
+        let dir = settings.config.backup_dir
+            .as_ref()
+            .ok_or_else(|| HttpError::bad_request(Some("BACKUP_DIR is not configured".to_string())))?;
+        let pool = storage
+            .pool()
+            .ok_or_else(|| HttpError::bad_request(Some("Storage has no database to back up".to_string())))?;
+
+        let path = backup::create(pool, Path::new(dir), settings.config.backup_retain)
+            .await
+            .log_error("Exception writing backup")?;
+        let bytes = std::fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .log_error("Exception reading backup size")?;
+
+        Ok(Json(json!({"path": path, "bytes": bytes})))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+    };
+
+    use axum::{
+        body::Body,
+        http::{
+            self,
+            Request,
+            StatusCode,
+        },
+    };
+    use envconfig::Envconfig;
+    use tokio;
+    use tower::ServiceExt;
+
+    use crate::{
+        api::Api,
+        backup,
+        config::{
+            Configuration,
+            ConfigurationHandle,
+            Settings,
+        },
+        database,
+        db::terraform::TerraformQuery,
+        storage::{
+            MemoryStorage,
+            SharedStorage,
+        },
+    };
+
+    fn authentication<S: AsRef<str>>(username: S, password: S) -> String {
+        format!(
+            "Basic {}",
+            base64::encode(
+                format!(
+                    "{}:{}",
+                    username.as_ref(),
+                    password.as_ref(),
+                )
+            )
+        )
+    }
+
+    #[tokio::test]
+    async fn test_backup() {
+        let dir = std::env::temp_dir()
+            .join(format!("tf-http-backend-admin-backup-{}", std::process::id()));
+        let mut hashmap = HashMap::new();
+
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir)
+            .unwrap();
+
+        hashmap.insert("DATABASE_URI".to_string(), dir.join("tf_state.db").to_str().unwrap().to_string());
+        hashmap.insert("TF_HTTP_USERNAME".to_string(), "asdf".to_string());
+        hashmap.insert("TF_HTTP_PASSWORD".to_string(), "asdf".to_string());
+        hashmap.insert("BACKUP_DIR".to_string(), dir.join("backups").to_str().unwrap().to_string());
+
+        let config = Configuration::init_from_hashmap(&hashmap)
+            .unwrap();
+        let pool = database::get_db_pool(&config)
+            .await
+            .unwrap();
+
+        database::MIGRATE.run(&pool)
+            .await
+            .unwrap();
+
+        let config = Arc::new(ConfigurationHandle::new(Settings::new(config).unwrap()));
+        let sqlite: SharedStorage = Arc::new(TerraformQuery::new(pool));
+        let memory: SharedStorage = Arc::new(MemoryStorage::new());
+
+        for (storage, password, status) in [
+            (sqlite.clone(), "wrong", StatusCode::UNAUTHORIZED),
+            (memory, "asdf", StatusCode::BAD_REQUEST),
+            (sqlite, "asdf", StatusCode::OK),
+        ] {
+            let router: axum::Router = Api::new(config.clone(), storage).into();
+            let request = Request::builder()
+                .uri("/admin/backups")
+                .method(http::Method::POST)
+                .header("AUTHORIZATION", authentication("asdf", password))
+                .body(Body::empty())
+                .expect("Failed to build request");
+
+            let response = router
+                .oneshot(request)
+                .await
+                .expect("Failed to call API");
+
+            assert_eq!(response.status(), status);
+        }
+
+        assert_eq!(backup::list(&dir.join("backups")).unwrap().len(), 1);
+    }
+}
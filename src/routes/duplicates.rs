@@ -0,0 +1,175 @@
+use axum::{
+    extract::Extension,
+    response::IntoResponse,
+    Json,
+};
+use axum_debug::debug_handler;
+use serde::Serialize;
+
+use crate::{
+    auth::Permission,
+    error::HttpError,
+    extractors::LoginExtractor,
+    inventory::{
+        Owner,
+        SharedInventory,
+    },
+};
+
+
+/// A duplicate as seen by a caller who may not read every workspace involved
+#[derive(Serialize)]
+pub struct DuplicateReport {
+    #[serde(rename = "type")]
+    resource_type: String,
+    id: String,
+    owners: Vec<Owner>,
+    /// Owners in workspaces the caller may not read
+    hidden_owners: usize,
+}
+
+
+pub struct DuplicatesRoute;
+
+
+impl DuplicatesRoute {
+    /// Lists objects managed by more than one workspace, for those involving a
+    /// workspace the caller may read
+    #[debug_handler]
+    pub async fn get(
+        LoginExtractor(principal): LoginExtractor,
+        Extension(inventory): Extension<SharedInventory>,
+    ) -> Result<impl IntoResponse, HttpError> {
+        let reports: Vec<_> = inventory
+            .duplicates()
+            .into_iter()
+            .filter_map(|duplicate| {
+                let total = duplicate.owners.len();
+                let owners: Vec<_> = duplicate.owners
+                    .into_iter()
+                    .filter(|owner| principal.allows(&owner.workspace, Permission::Read))
+                    .collect();
+
+                if owners.is_empty() {
+                    return None;
+                }
+
+                Some(DuplicateReport {
+                    resource_type: duplicate.resource_type,
+                    id: duplicate.id,
+                    hidden_owners: total - owners.len(),
+                    owners,
+                })
+            })
+            .collect();
+
+        Ok(Json(reports))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{
+            self,
+            Request,
+            StatusCode,
+        },
+    };
+    use hyper;
+    use serde_json::{
+        Value,
+        json,
+    };
+    use tokio;
+    use tower::ServiceExt;
+
+    use crate::{
+        routes::fixture::{
+            self,
+            TempFile,
+        },
+        storage::{
+            MemoryStorage,
+            Storage,
+        },
+    };
+
+    async fn api(policy: &str) -> (axum::Router, Option<TempFile>) {
+        let storage = Arc::new(MemoryStorage::new());
+
+        for id in ["prod-logs", "staging-logs"] {
+            storage.lock(id, "abcd", "{}")
+                .await
+                .unwrap();
+        }
+
+        fixture::router(storage, Some(&fixture::grant("dashboard", "prod-*", "read")), &[("DUPLICATE_RESOURCES", policy)])
+    }
+
+    fn post(id: &str) -> Request<Body> {
+        let state = json!({
+            "version": 4,
+            "terraform_version": "1.0.11",
+            "serial": 1,
+            "lineage": id,
+            "resources": [{
+                "mode": "managed",
+                "type": "aws_s3_bucket",
+                "name": "logs",
+                "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+                "instances": [{"schema_version": 0, "attributes": {"id": "shared-logs"}}],
+            }],
+        });
+
+        Request::builder()
+            .uri(format!("/terraform/{}?ID=abcd", id))
+            .method(http::Method::POST)
+            .header("Authorization", format!("Basic {}", base64::encode("asdf:asdf")))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(state.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_duplicates() {
+        let (router, _auth_file) = api("warn")
+            .await;
+
+        for id in ["prod-logs", "staging-logs"] {
+            assert_eq!(router.clone().oneshot(post(id)).await.unwrap().status(), StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .uri("/duplicates")
+            .header("Authorization", "Bearer dashboard-token")
+            .body(Body::empty())
+            .unwrap();
+        let response = router
+            .oneshot(request)
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body)
+            .unwrap();
+
+        assert_eq!(body, json!([{
+            "type": "aws_s3_bucket",
+            "id": "shared-logs",
+            "owners": [{"workspace": "prod-logs", "address": "aws_s3_bucket.logs"}],
+            "hidden_owners": 1,
+        }]));
+
+        let (router, _auth_file) = api("reject")
+            .await;
+
+        assert_eq!(router.clone().oneshot(post("prod-logs")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(router.clone().oneshot(post("staging-logs")).await.unwrap().status(), StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,298 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{
+        Extension,
+        Query,
+    },
+    http::HeaderMap,
+    response::{
+        IntoResponse,
+        sse::{
+            self,
+            KeepAlive,
+            Sse,
+        },
+    },
+};
+use axum_debug::debug_handler;
+use futures::stream::{
+    self,
+    Stream,
+    StreamExt,
+};
+use serde::Deserialize;
+use tokio::sync::broadcast::{
+    Receiver,
+    error::RecvError,
+};
+
+use crate::{
+    auth::{
+        Permission,
+        Principal,
+    },
+    error::HttpError,
+    events::{
+        Sequenced,
+        SharedEvents,
+    },
+    extractors::LoginExtractor,
+    lifecycle::SharedLifecycle,
+};
+
+
+/// Sent in place of events the subscriber may have missed
+const RESYNC_EVENT: &str = "resync";
+
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Only workspaces whose id starts with this
+    #[serde(default)]
+    prefix: String,
+}
+
+
+pub struct EventsRoute;
+
+
+/// What an open stream needs to forward events as they are published
+struct Subscriber {
+    receiver: Receiver<Sequenced>,
+    events: SharedEvents,
+    lifecycle: SharedLifecycle,
+    principal: Principal,
+    prefix: String,
+}
+
+
+impl EventsRoute {
+    /// Streams events for the workspaces the caller may read. A client
+    /// reconnecting with `Last-Event-ID` is first sent the events it missed,
+    /// or a `resync` event if they are no longer known.
+    #[debug_handler]
+    pub async fn get(
+        LoginExtractor(principal): LoginExtractor,
+        Extension(events): Extension<SharedEvents>,
+        Extension(lifecycle): Extension<SharedLifecycle>,
+        Query(query): Query<EventsQuery>,
+        headers: HeaderMap,
+    ) -> Result<impl IntoResponse, HttpError> {
+        let last_event_id = headers
+            .get("Last-Event-ID")
+            .map(|value| value.to_str())
+            .transpose()
+            .map_err(|_| HttpError::BadRequest("Malformed Last-Event-ID".to_owned()))?;
+
+        let subscription = events.subscribe(last_event_id);
+        let mut initial = Vec::new();
+
+        if subscription.resync {
+            initial.push(resync_event());
+        }
+
+        initial.extend(
+            subscription
+                .replay
+                .iter()
+                .filter(|sequenced| visible(&principal, &query.prefix, sequenced))
+                .map(|sequenced| sse_event(&events, sequenced))
+        );
+
+        let subscriber = Subscriber {
+            receiver: subscription.receiver,
+            events,
+            lifecycle,
+            principal,
+            prefix: query.prefix,
+        };
+
+        let stream = stream::iter(initial)
+            .chain(live(subscriber))
+            .map(Ok::<_, Infallible>);
+
+        Ok(Sse::new(stream).keep_alive(KeepAlive::new()))
+    }
+}
+
+
+/// Events as they are published, ending when the server starts draining so
+/// open streams do not hold up shutdown
+fn live(subscriber: Subscriber) -> impl Stream<Item = sse::Event> {
+    stream::unfold(subscriber, |mut subscriber| async move {
+        loop {
+            let received = tokio::select! {
+                _ = subscriber.lifecycle.until_draining() => None,
+                received = subscriber.receiver.recv() => Some(received),
+            };
+
+            let event = match received {
+                None | Some(Err(RecvError::Closed)) => return None,
+                // the subscriber fell too far behind and events were dropped
+                Some(Err(RecvError::Lagged(_))) => resync_event(),
+                Some(Ok(sequenced)) if visible(&subscriber.principal, &subscriber.prefix, &sequenced) => {
+                    sse_event(&subscriber.events, &sequenced)
+                },
+                Some(Ok(_)) => continue,
+            };
+
+            return Some((event, subscriber));
+        }
+    })
+}
+
+
+fn visible(principal: &Principal, prefix: &str, sequenced: &Sequenced) -> bool {
+    let workspace = &sequenced.event.workspace;
+
+    workspace.starts_with(prefix) && principal.allows(workspace, Permission::Read)
+}
+
+
+fn sse_event(events: &SharedEvents, sequenced: &Sequenced) -> sse::Event {
+    sse::Event::default()
+        .id(events.id(sequenced.sequence))
+        .event(sequenced.event.event.as_str())
+        .json_data(&sequenced.event)
+        .expect("Events always serialize")
+}
+
+
+fn resync_event() -> sse::Event {
+    sse::Event::default()
+        .event(RESYNC_EVENT)
+        .data("Events may have been missed; fetch the workspaces again")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::Duration,
+    };
+
+    use axum::{
+        body::{
+            Body,
+            BoxBody,
+        },
+        http::{
+            self,
+            Request,
+            StatusCode,
+        },
+    };
+    use hyper::body::HttpBody;
+    use tokio;
+    use tower::ServiceExt;
+
+    use crate::{
+        routes::fixture,
+        storage::MemoryStorage,
+    };
+
+    async fn next_chunk(body: &mut BoxBody) -> String {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("No event within 5s")
+            .unwrap()
+            .unwrap();
+
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    fn lock_request(id: &str, authorization: &str) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/terraform/{}/lock", id))
+            .header("Authorization", authorization)
+            .header("Content-Type", "application/json")
+            .body(Body::from(format!("{{\"ID\": \"lock-{}\"}}", id)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (api, _auth_file) = fixture::api(Arc::new(MemoryStorage::new()), Some(&fixture::grant("dashboard", "prod-*", "read")), &[]);
+        let lifecycle = api.lifecycle();
+        let router: axum::Router = api.into();
+        let admin = format!("Basic {}", base64::encode("asdf:asdf"));
+
+        let subscribe = |last_event_id: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/events?prefix=prod-")
+                .header("Authorization", "Bearer dashboard-token");
+
+            if let Some(id) = last_event_id {
+                request = request.header("Last-Event-ID", id);
+            }
+
+            request
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = router.clone()
+            .oneshot(subscribe(None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "text/event-stream");
+
+        let mut body = response.into_body();
+
+        // outside the prefix, outside the ACL, then visible
+        for id in ["staging-network", "prod-network"] {
+            let response = router.clone()
+                .oneshot(lock_request(id, &admin))
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let chunk = next_chunk(&mut body)
+            .await;
+
+        assert!(chunk.contains("event:lock.acquired"));
+        assert!(chunk.contains("\"workspace\":\"prod-network\""));
+
+        let id = chunk
+            .lines()
+            .find_map(|line| line.strip_prefix("id:"))
+            .unwrap()
+            .to_string();
+
+        router.clone()
+            .oneshot(lock_request("prod-dns", &admin))
+            .await
+            .unwrap();
+
+        // resuming replays what was missed
+        let mut resumed = router.clone()
+            .oneshot(subscribe(Some(&id)))
+            .await
+            .unwrap()
+            .into_body();
+
+        assert!(next_chunk(&mut resumed).await.contains("\"workspace\":\"prod-dns\""));
+
+        let mut unknown = router.clone()
+            .oneshot(subscribe(Some("1-1")))
+            .await
+            .unwrap()
+            .into_body();
+
+        assert!(next_chunk(&mut unknown).await.contains("event:resync"));
+
+        assert!(next_chunk(&mut body).await.contains("prod-dns"));
+
+        // draining ends open streams
+        lifecycle.start_draining();
+
+        assert!(tokio::time::timeout(Duration::from_secs(5), body.data()).await.unwrap().is_none());
+    }
+}
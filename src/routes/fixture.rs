@@ -0,0 +1,111 @@
+//! Shared by the route tests: the API over a given storage, with `asdf:asdf`
+//! as the administrator and optionally an `AUTH_FILE` granting tokens access
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+    },
+};
+
+use envconfig::Envconfig;
+
+use crate::{
+    api::Api,
+    auth::sha256_hex,
+    config::{
+        Configuration,
+        ConfigurationHandle,
+        Settings,
+    },
+    storage::SharedStorage,
+};
+
+
+/// Keeps the files of tests running at the same time apart
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+
+/// A file in the temp dir, removed when dropped
+pub struct TempFile(PathBuf);
+
+
+impl TempFile {
+    pub fn new(contents: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("tf-http-backend-{}-{}", std::process::id(), FILES.fetch_add(1, Ordering::SeqCst)));
+
+        fs::write(&path, contents)
+            .unwrap();
+
+        Self(path)
+    }
+
+    pub fn path(&self) -> &str {
+        self.0
+            .to_str()
+            .unwrap()
+    }
+}
+
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+
+/// `AUTH_FILE` contents granting a token named `name`, whose secret is
+/// `<name>-token`, one permission on `workspaces`
+pub fn grant(name: &str, workspaces: &str, permission: &str) -> String {
+    format!(
+        "[[tokens]]\nname = \"{}\"\nsha256 = \"{}\"\n\n\
+        [[acls]]\nprincipals = [\"{}\"]\nworkspaces = [\"{}\"]\npermissions = [\"{}\"]\n",
+        name,
+        sha256_hex(&format!("{}-token", name)),
+        name,
+        workspaces,
+        permission,
+    )
+}
+
+
+/// The API over `storage` with the given variables set, and an `AUTH_FILE`
+/// holding `auth` if any. The file is removed when the returned guard drops.
+pub fn api(storage: SharedStorage, auth: Option<&str>, variables: &[(&str, &str)]) -> (Api, Option<TempFile>) {
+    let auth_file = auth.map(TempFile::new);
+    let mut hashmap = HashMap::new();
+
+    hashmap.insert("DATABASE_URI".to_string(), "memory://".to_string());
+    hashmap.insert("TF_HTTP_USERNAME".to_string(), "asdf".to_string());
+    hashmap.insert("TF_HTTP_PASSWORD".to_string(), "asdf".to_string());
+
+    if let Some(file) = &auth_file {
+        hashmap.insert("AUTH_FILE".to_string(), file.path().to_string());
+    }
+
+    for (key, value) in variables {
+        hashmap.insert(key.to_string(), value.to_string());
+    }
+
+    let config = Configuration::init_from_hashmap(&hashmap)
+        .unwrap();
+    let api = Api::new(Arc::new(ConfigurationHandle::new(Settings::new(config).unwrap())), storage);
+
+    (api, auth_file)
+}
+
+
+/// As `api`, as a router
+pub fn router(storage: SharedStorage, auth: Option<&str>, variables: &[(&str, &str)]) -> (axum::Router, Option<TempFile>) {
+    let (api, auth_file) = api(storage, auth, variables);
+
+    (api.into(), auth_file)
+}
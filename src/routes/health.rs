@@ -0,0 +1,179 @@
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use axum_debug::debug_handler;
+use serde_json::json;
+
+use crate::{
+    database,
+    lifecycle::SharedLifecycle,
+    storage::SharedStorage,
+};
+
+
+pub struct HealthRoute;
+
+
+impl HealthRoute {
+    /// Liveness; succeeds whenever the process is able to serve requests
+    #[debug_handler]
+    pub async fn live() -> impl IntoResponse {
+        Json(json!({"status": "ok"}))
+    }
+
+    /// Readiness; fails with a reason while draining or if the database is
+    /// unreachable or not fully migrated
+    #[debug_handler]
+    pub async fn ready(
+        Extension(storage): Extension<SharedStorage>,
+        Extension(lifecycle): Extension<SharedLifecycle>,
+    ) -> impl IntoResponse {
+        match not_ready_reason(&storage, &lifecycle).await {
+            None => (StatusCode::OK, Json(json!({"status": "ready"}))),
+            Some(reason) => {
+                tracing::warn!("Not ready: {}", reason);
+
+                (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"status": "unavailable", "reason": reason})))
+            },
+        }
+    }
+}
+
+
+async fn not_ready_reason(storage: &SharedStorage, lifecycle: &SharedLifecycle) -> Option<String> {
+    if lifecycle.is_draining() {
+        return Some("Server is draining".to_string());
+    }
+
+    let pool = storage.pool()?;
+
+    if let Err(e) = sqlx::query("SELECT 1").execute(pool).await {
+        return Some(format!("Database is unavailable: {}", e));
+    }
+
+    match database::pending_migrations(pool).await {
+        Ok(pending) if pending.is_empty() => None,
+        Ok(pending) => Some(format!("{} database migrations are not applied", pending.len())),
+        Err(e) => Some(format!("Failed to read applied migrations: {}", e)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+    };
+
+    use axum::{
+        body::Body,
+        http::{
+            self,
+            Request,
+            StatusCode,
+        },
+    };
+    use envconfig::Envconfig;
+    use hyper;
+    use serde_json::Value;
+    use tokio;
+    use tower::ServiceExt;
+
+    use crate::{
+        api::Api,
+        config::{
+            Configuration,
+            ConfigurationHandle,
+            Settings,
+            SharedConfiguration,
+        },
+        database,
+        db::terraform::TerraformQuery,
+        storage::MemoryStorage,
+    };
+
+    fn default_config() -> SharedConfiguration {
+        let mut hashmap = HashMap::new();
+
+        hashmap.insert("DATABASE_URI".to_string(), "sqlite::memory:".to_string());
+        hashmap.insert("TF_HTTP_USERNAME".to_string(), "asdf".to_string());
+        hashmap.insert("TF_HTTP_PASSWORD".to_string(), "asdf".to_string());
+
+        let config = Configuration::init_from_hashmap(&hashmap)
+            .unwrap();
+
+        Arc::new(ConfigurationHandle::new(Settings::new(config).unwrap()))
+    }
+
+
+    async fn call(router: &axum::Router, uri: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .uri(uri)
+            .method(http::Method::GET)
+            .body(Body::empty())
+            .expect("Failed to build request");
+
+        let response = router.clone()
+            .oneshot(request)
+            .await
+            .expect("Failed to call API");
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let config = default_config();
+        let api = Api::new(config, Arc::new(MemoryStorage::new()));
+        let router: axum::Router = api.into();
+
+        let (status, _) = call(&router, "/healthz")
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+    }
+
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let config = default_config();
+        let pool = database::get_db_pool(&config.current().config)
+            .await
+            .unwrap();
+        let api = Api::new(config, Arc::new(TerraformQuery::new(pool.clone())));
+        let lifecycle = api.lifecycle();
+        let router: axum::Router = api.into();
+
+        let (status, body) = call(&router, "/readyz")
+            .await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["reason"].as_str().unwrap().contains("migrations"));
+
+        database::MIGRATE.run(&pool)
+            .await
+            .unwrap();
+
+        let (status, _) = call(&router, "/readyz")
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+
+        lifecycle.start_draining();
+
+        let (status, body) = call(&router, "/readyz")
+            .await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reason"], "Server is draining");
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{
+        Extension,
+        Query,
+    },
+    response::IntoResponse,
+    Json,
+};
+use axum_debug::debug_handler;
+use serde::Serialize;
+
+use crate::{
+    auth::Permission,
+    error::HttpError,
+    extractors::LoginExtractor,
+    inventory::{
+        SharedInventory,
+        VersionFilter,
+        WorkspaceVersions,
+    },
+};
+
+
+/// How many of the reported workspaces use each terraform release and
+/// provider, followed by the details of each workspace
+#[derive(Serialize)]
+pub struct VersionReport {
+    terraform_versions: BTreeMap<String, usize>,
+    providers: BTreeMap<String, usize>,
+    workspaces: Vec<WorkspaceVersions>,
+}
+
+
+pub struct InventoryRoute;
+
+
+impl InventoryRoute {
+    /// Reports the terraform releases and providers of the states the caller
+    /// may read, e.g. `GET /inventory/versions?terraform_below=0.14`
+    #[debug_handler]
+    pub async fn versions(
+        LoginExtractor(principal): LoginExtractor,
+        Extension(inventory): Extension<SharedInventory>,
+        Query(filter): Query<VersionFilter>,
+    ) -> Result<impl IntoResponse, HttpError> {
+        filter.validate()
+            .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+
+        let workspaces: Vec<_> = inventory
+            .versions(&filter)
+            .into_iter()
+            .filter(|versions| principal.allows(&versions.workspace, Permission::Read))
+            .collect();
+
+        let mut terraform_versions = BTreeMap::new();
+        let mut providers = BTreeMap::new();
+
+        for versions in workspaces.iter() {
+            let terraform = versions.terraform_version
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string());
+
+            *terraform_versions.entry(terraform).or_default() += 1;
+
+            for provider in versions.providers.iter() {
+                *providers.entry(provider.clone()).or_default() += 1;
+            }
+        }
+
+        Ok(Json(VersionReport {
+            terraform_versions,
+            providers,
+            workspaces,
+        }))
+    }
+}
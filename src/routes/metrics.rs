@@ -0,0 +1,140 @@
+use axum::{
+    extract::Extension,
+    response::{
+        Headers,
+        IntoResponse,
+    },
+};
+use axum_debug::debug_handler;
+use http::header::CONTENT_TYPE;
+use prometheus::TEXT_FORMAT;
+
+use crate::{
+    error::HttpError,
+    metrics::SharedMetrics,
+    storage::SharedStorage,
+};
+
+
+pub struct MetricsRoute;
+
+
+impl MetricsRoute {
+    /// Prometheus scrape endpoint; unauthenticated like most exporters
+    #[debug_handler]
+    pub async fn get(
+        Extension(metrics): Extension<SharedMetrics>,
+        Extension(storage): Extension<SharedStorage>,
+    ) -> Result<impl IntoResponse, HttpError> {
+        let body = metrics
+            .render(&storage)
+            .await
+            .map_err(|e| {
+                tracing::error!("Exception rendering metrics: {:#?}", e);
+
+                HttpError::from(e)
+            })?;
+
+        Ok((Headers(vec![(CONTENT_TYPE, TEXT_FORMAT)]), body))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+    };
+
+    use axum::{
+        body::Body,
+        http::{
+            self,
+            Request,
+            StatusCode,
+        },
+    };
+    use envconfig::Envconfig;
+    use hyper;
+    use serde_json::json;
+    use tokio;
+    use tower::ServiceExt;
+
+    use crate::{
+        api::Api,
+        config::{
+            Configuration,
+            ConfigurationHandle,
+            Settings,
+            SharedConfiguration,
+        },
+        storage::{
+            MemoryStorage,
+            Storage,
+        },
+    };
+
+    fn default_config() -> SharedConfiguration {
+        let mut hashmap = HashMap::new();
+
+        hashmap.insert("DATABASE_URI".to_string(), "memory://".to_string());
+        hashmap.insert("TF_HTTP_USERNAME".to_string(), "asdf".to_string());
+        hashmap.insert("TF_HTTP_PASSWORD".to_string(), "asdf".to_string());
+
+        let config = Configuration::init_from_hashmap(&hashmap)
+            .unwrap();
+
+        Arc::new(ConfigurationHandle::new(Settings::new(config).unwrap()))
+    }
+
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let config = default_config();
+        let storage = Arc::new(MemoryStorage::new());
+        let api = Api::new(config, storage.clone());
+        let router: axum::Router = api.into();
+
+        storage.lock("105", "abcd", &json!({"ID": "abcd"}).to_string())
+            .await
+            .expect("Failed to lock resource");
+
+        let request = Request::builder()
+            .uri("/terraform/105")
+            .method(http::Method::GET)
+            .header("AUTHORIZATION", "Basic d3Jvbmc6d3Jvbmc=")
+            .body(Body::empty())
+            .expect("Failed to build request");
+
+        let response = router.clone()
+            .oneshot(request)
+            .await
+            .expect("Failed to call API");
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .method(http::Method::GET)
+            .body(Body::empty())
+            .expect("Failed to build request");
+
+        let response = router
+            .oneshot(request)
+            .await
+            .expect("Failed to call API");
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec())
+            .unwrap();
+
+        assert!(body.contains("auth_failures_total 1"));
+        assert!(body.contains("terraform_locks_held 1"));
+        assert!(body.contains("http_requests_total{method=\"GET\",route=\"/terraform/:id\",status=\"401\"} 1"));
+    }
+}
@@ -1 +1,10 @@
+pub mod admin;
+pub mod duplicates;
+pub mod events;
+#[cfg(test)]
+pub(crate) mod fixture;
+pub mod health;
+pub mod inventory;
+pub mod metrics;
+pub mod search;
 pub mod terraform;
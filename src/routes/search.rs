@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{
+        Extension,
+        Query,
+    },
+    response::IntoResponse,
+    Json,
+};
+use axum_debug::debug_handler;
+
+use crate::{
+    auth::Permission,
+    error::HttpError,
+    extractors::LoginExtractor,
+    inventory::{
+        Search,
+        SharedInventory,
+    },
+};
+
+
+pub struct SearchRoute;
+
+
+impl SearchRoute {
+    /// Finds resource instances across every workspace the caller may read,
+    /// e.g. `GET /search?type=aws_s3_bucket&attr.id=logs`
+    #[debug_handler]
+    pub async fn get(
+        LoginExtractor(principal): LoginExtractor,
+        Extension(inventory): Extension<SharedInventory>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, HttpError> {
+        let search = Search::from_params(&params)
+            .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+
+        let found: Vec<_> = inventory
+            .search(&search)
+            .into_iter()
+            .filter(|resource| principal.allows(&resource.workspace, Permission::Read))
+            .collect();
+
+        Ok(Json(found))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{
+            self,
+            Request,
+            StatusCode,
+        },
+    };
+    use hyper;
+    use serde_json::{
+        Value,
+        json,
+    };
+    use tokio;
+    use tower::ServiceExt;
+
+    use crate::{
+        routes::fixture,
+        storage::{
+            MemoryStorage,
+            Storage,
+        },
+    };
+
+    fn state(bucket: &str) -> Value {
+        json!({
+            "version": 4,
+            "terraform_version": "1.0.11",
+            "serial": 1,
+            "lineage": bucket,
+            "resources": [{
+                "mode": "managed",
+                "type": "aws_s3_bucket",
+                "name": "logs",
+                "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+                "instances": [{"schema_version": 0, "attributes": {"id": bucket}}],
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let storage = Arc::new(MemoryStorage::new());
+        let (router, _auth_file) = fixture::router(storage.clone(), Some(&fixture::grant("dashboard", "prod-*", "read")), &[]);
+        let admin = format!("Basic {}", base64::encode("asdf:asdf"));
+
+        for id in ["prod-logs", "staging-logs"] {
+            storage.lock(id, "abcd", "{}")
+                .await
+                .unwrap();
+
+            let request = Request::builder()
+                .uri(format!("/terraform/{}?ID=abcd", id))
+                .method(http::Method::POST)
+                .header("Authorization", &admin)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(state(id).to_string()))
+                .unwrap();
+            let response = router.clone()
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let search = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", "Bearer dashboard-token")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = router.clone()
+            .oneshot(search("/search?type=aws_s3_bucket"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body)
+            .unwrap();
+
+        // staging-logs is outside the token's ACL
+        assert_eq!(body, json!([{
+            "workspace": "prod-logs",
+            "address": "aws_s3_bucket.logs",
+            "mode": "managed",
+            "type": "aws_s3_bucket",
+            "provider": "registry.terraform.io/hashicorp/aws",
+            "attributes": {"id": "prod-logs"},
+        }]));
+
+        let response = router.clone()
+            .oneshot(search("/search?attr.tags=x"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
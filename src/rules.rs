@@ -0,0 +1,312 @@
+use std::{
+    collections::{
+        BTreeSet,
+        HashSet,
+    },
+    fmt,
+    path::Path,
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    auth::glob_match,
+    config::{
+        self,
+        ConfigError,
+        Configuration,
+    },
+    models::state::State,
+};
+
+
+/// Whether violating a rule rejects the write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RulesMode {
+    Enforce,
+    /// Violations are logged and the state is written anyway
+    DryRun,
+}
+
+
+/// A check on every state written to the matching workspaces, configured in
+/// `RULES_FILE`. Each check set on the rule applies to the resources whose
+/// type matches `types`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    /// Workspace id patterns where `*` matches any run of characters
+    #[serde(default = "everything")]
+    workspaces: Vec<String>,
+    /// Resource type patterns where `*` matches any run of characters
+    #[serde(default = "everything")]
+    types: Vec<String>,
+    /// No instance of the resources may be removed
+    #[serde(default)]
+    protect: bool,
+    /// At most this many instances of the resources may be removed by one write
+    max_removals: Option<usize>,
+    /// Top level attributes none of the resources may have a value for
+    #[serde(default)]
+    forbid_attributes: Vec<String>,
+}
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub rule: String,
+    pub message: String,
+}
+
+
+impl FromStr for RulesMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "enforce" => Ok(Self::Enforce),
+            "dry-run" => Ok(Self::DryRun),
+            other => Err(format!("Unknown rules mode: {}", other)),
+        }
+    }
+}
+
+
+impl fmt::Display for RulesMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            Self::Enforce => "enforce",
+            Self::DryRun => "dry-run",
+        };
+
+        write!(f, "{}", mode)
+    }
+}
+
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rule {}: {}", self.rule, self.message)
+    }
+}
+
+
+impl Rule {
+    pub fn from_config(config: &Configuration) -> Result<Vec<Self>, ConfigError> {
+        match &config.rules_file {
+            Some(path) => Self::from_file(Path::new(path)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Vec<Self>, ConfigError> {
+        let file = config::parse_file::<RulesFile>(path)?;
+        let mut names = HashSet::new();
+
+        for rule in file.rules.iter() {
+            if !names.insert(&rule.name) {
+                return Err(ConfigError::Invalid(format!("Rule {} is defined more than once", rule.name)));
+            }
+
+            if !rule.protect && rule.max_removals.is_none() && rule.forbid_attributes.is_empty() {
+                return Err(ConfigError::Invalid(format!("Rule {} checks nothing; set protect, max_removals or forbid_attributes", rule.name)));
+            }
+        }
+
+        Ok(file.rules)
+    }
+
+    fn covers(&self, resource_type: &str) -> bool {
+        self.types
+            .iter()
+            .any(|pattern| glob_match(pattern, resource_type))
+    }
+
+    fn check(&self, previous: Option<&State>, state: &State) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let violation = |message: String| Violation {
+            rule: self.name.clone(),
+            message,
+        };
+
+        let remaining = addresses(self, state);
+        let removed: Vec<_> = previous
+            .map(|previous| addresses(self, previous))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|address| !remaining.contains(address))
+            .collect();
+
+        if self.protect && !removed.is_empty() {
+            violations.push(violation(format!("{} may not be removed", removed.join(", "))));
+        }
+
+        match self.max_removals {
+            Some(limit) if removed.len() > limit => {
+                violations.push(violation(format!("{} instances would be removed, more than the {} allowed", removed.len(), limit)));
+            },
+            _ => (),
+        }
+
+        for resource in state.resources.iter().filter(|resource| self.covers(&resource.resource_type)) {
+            for instance in resource.instances.iter() {
+                let present: Vec<_> = self.forbid_attributes
+                    .iter()
+                    .filter(|name| {
+                        instance.attributes
+                            .as_ref()
+                            .and_then(|attributes| attributes.get(name.as_str()))
+                            .map(has_value)
+                            .unwrap_or(false)
+                    })
+                    .map(|name| name.as_str())
+                    .collect();
+
+                if !present.is_empty() {
+                    violations.push(violation(format!("{} may not set {}", resource.address(instance), present.join(", "))));
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+
+/// Checks a state about to be written to `workspace` over `previous`, the
+/// state it replaces, against every rule that applies to the workspace
+pub fn evaluate(rules: &[Rule], workspace: &str, previous: Option<&State>, state: &State) -> Vec<Violation> {
+    rules
+        .iter()
+        .filter(|rule| rule.workspaces.iter().any(|pattern| glob_match(pattern, workspace)))
+        .flat_map(|rule| rule.check(previous, state))
+        .collect()
+}
+
+
+/// Addresses of the instances of the resources a rule covers
+fn addresses(rule: &Rule, state: &State) -> BTreeSet<String> {
+    state.resources
+        .iter()
+        .filter(|resource| rule.covers(&resource.resource_type))
+        .flat_map(|resource| resource.instances.iter().map(move |instance| resource.address(instance)))
+        .collect()
+}
+
+
+fn has_value(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::String(value) => !value.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(values) => !values.is_empty(),
+        _ => true,
+    }
+}
+
+
+fn everything() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use super::{
+        Rule,
+        evaluate,
+    };
+    use crate::models::state::State;
+
+    fn state(databases: &[&str], password: Option<&str>) -> State {
+        let instances: Vec<_> = databases
+            .iter()
+            .map(|name| json!({"index_key": name, "schema_version": 1, "attributes": {"id": name, "password": password}}))
+            .collect();
+
+        State::from_value(&json!({
+            "version": 4,
+            "terraform_version": "1.0.11",
+            "serial": 1,
+            "lineage": "abcd",
+            "resources": [{
+                "mode": "managed",
+                "type": "aws_db_instance",
+                "name": "main",
+                "each": "map",
+                "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+                "instances": instances,
+            }],
+        }))
+            .unwrap()
+    }
+
+    fn rules(contents: &str) -> Vec<Rule> {
+        let path = std::env::temp_dir()
+            .join(format!("tf-http-backend-rules-{}-{}.toml", std::process::id(), contents.len()));
+
+        fs::write(&path, contents)
+            .unwrap();
+
+        Rule::from_file(&path)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let rules = rules(
+            "[[rules]]\nname = \"keep-databases\"\nworkspaces = [\"prod-*\"]\ntypes = [\"aws_db_*\"]\nprotect = true\n\n\
+            [[rules]]\nname = \"small-applies\"\nmax_removals = 1\n\n\
+            [[rules]]\nname = \"no-passwords\"\nforbid_attributes = [\"password\"]\n",
+        );
+        let previous = state(&["a", "b", "c"], None);
+
+        assert!(evaluate(&rules, "prod-db", Some(&previous), &previous).is_empty());
+
+        let violations: Vec<_> = evaluate(&rules, "prod-db", Some(&previous), &state(&["a"], None))
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect();
+
+        assert_eq!(violations, vec![
+            "rule keep-databases: aws_db_instance.main[\"b\"], aws_db_instance.main[\"c\"] may not be removed",
+            "rule small-applies: 2 instances would be removed, more than the 1 allowed",
+        ]);
+
+        // outside the protected workspaces
+        assert_eq!(evaluate(&rules, "staging-db", Some(&previous), &state(&["a", "b"], None)).len(), 0);
+
+        let violations = evaluate(&rules, "staging-db", None, &state(&["a"], Some("hunter2")));
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].message, "aws_db_instance.main[\"a\"] may not set password");
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir()
+            .join(format!("tf-http-backend-rules-invalid-{}.toml", std::process::id()));
+
+        for contents in ["[[rules]]\nname = \"noop\"\n", "[[rules]]\nname = \"a\"\nprotect = true\n\n[[rules]]\nname = \"a\"\nprotect = true\n"] {
+            fs::write(&path, contents)
+                .unwrap();
+
+            assert!(Rule::from_file(&path).is_err());
+        }
+    }
+}
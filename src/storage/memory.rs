@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use axum::async_trait;
+use chrono::Utc;
+
+use super::{
+    StateSummary,
+    StateVersion,
+    Storage,
+    StorageError,
+};
+use crate::{
+    db::terraform::{
+        MaybeConflictError,
+        TerraformLockRow,
+        TerraformRow,
+    },
+    history::HistoryMode,
+};
+
+
+/// Keeps states and locks in process memory; everything is lost on restart.
+/// Versions are only retained when history is enabled, and always in full.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    states: Mutex<HashMap<String, Vec<(i64, TerraformRow)>>>,
+    locks: Mutex<HashMap<String, TerraformLockRow>>,
+    history: HistoryMode,
+    retention: i64,
+}
+
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self {
+            states: Mutex::new(HashMap::new()),
+            locks: Mutex::new(HashMap::new()),
+            history: HistoryMode::None,
+            retention: 0,
+        }
+    }
+
+    /// Retains the latest `retention` versions of each state, or all of them
+    /// if it is 0, unless `history` is `None`
+    pub fn with_history(self, history: HistoryMode, retention: i64) -> Self {
+        Self {
+            history,
+            retention: retention.max(0),
+            ..self
+        }
+    }
+}
+
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get(&self, id: &str) -> Result<Option<TerraformRow>, StorageError> {
+        let states = self.states
+            .lock()
+            .expect("MemoryStorage states mutex poisoned");
+
+        Ok(states.get(id).and_then(|versions| versions.last()).map(|(_, row)| row.clone()))
+    }
+
+    async fn create_or_replace(&self, id: &str, state: &str) -> Result<TerraformRow, StorageError> {
+        let row = TerraformRow {
+            id: id.to_string(),
+            state: state.to_string(),
+            last_update_ts: Utc::now().naive_utc(),
+        };
+
+        let mut states = self.states
+            .lock()
+            .expect("MemoryStorage states mutex poisoned");
+        let versions = states
+            .entry(id.to_string())
+            .or_default();
+        let version = versions
+            .last()
+            .map(|(version, _)| version + 1)
+            .unwrap_or(1);
+
+        versions.push((version, row.clone()));
+
+        // the last entry is the current state, which is always kept
+        let retained = match self.history {
+            HistoryMode::None => 1,
+            _ if self.retention > 0 => self.retention as usize,
+            _ => versions.len(),
+        };
+
+        versions.drain(..versions.len() - retained.min(versions.len()));
+
+        Ok(row)
+    }
+
+    async fn list(&self) -> Result<Vec<StateSummary>, StorageError> {
+        let states = self.states
+            .lock()
+            .expect("MemoryStorage states mutex poisoned");
+
+        let mut summaries: Vec<_> = states
+            .iter()
+            .filter_map(|(id, versions)| {
+                versions.last().map(|(_, row)| StateSummary {
+                    id: id.clone(),
+                    last_update_ts: row.last_update_ts,
+                })
+            })
+            .collect();
+
+        summaries.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(summaries)
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersion>, StorageError> {
+        let states = self.states
+            .lock()
+            .expect("MemoryStorage states mutex poisoned");
+
+        if self.history == HistoryMode::None {
+            return Ok(Vec::new());
+        }
+
+        let versions = states
+            .get(id)
+            .map(|versions| {
+                versions
+                    .iter()
+                    .map(|(version, row)| StateVersion {
+                        version: *version,
+                        last_update_ts: row.last_update_ts,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(versions)
+    }
+
+    async fn get_version(&self, id: &str, version: i64) -> Result<Option<TerraformRow>, StorageError> {
+        let states = self.states
+            .lock()
+            .expect("MemoryStorage states mutex poisoned");
+
+        if self.history == HistoryMode::None {
+            return Ok(None);
+        }
+
+        let row = states
+            .get(id)
+            .and_then(|versions| versions.iter().find(|(stored, _)| *stored == version))
+            .map(|(_, row)| row.clone());
+
+        Ok(row)
+    }
+
+    async fn get_lock_by_terraform_id(&self, terraform_id: &str) -> Result<Option<TerraformLockRow>, StorageError> {
+        let locks = self.locks
+            .lock()
+            .expect("MemoryStorage locks mutex poisoned");
+
+        Ok(locks.get(terraform_id).cloned())
+    }
+
+    async fn lock(&self, terraform_id: &str, lock_id: &str, state: &str) -> Result<TerraformLockRow, MaybeConflictError> {
+        let mut locks = self.locks
+            .lock()
+            .expect("MemoryStorage locks mutex poisoned");
+
+        let row = locks
+            .entry(terraform_id.to_string())
+            .or_insert_with(|| TerraformLockRow {
+                id: lock_id.to_string(),
+                terraform_id: terraform_id.to_string(),
+                state: state.to_string(),
+                last_update_ts: Utc::now().naive_utc(),
+            })
+            .clone();
+
+        if row.id != lock_id {
+            Err(MaybeConflictError::Conflict(row))
+        } else {
+            Ok(row)
+        }
+    }
+
+    async fn unlock(&self, terraform_id: &str, lock_id: &str) -> Result<bool, StorageError> {
+        let mut locks = self.locks
+            .lock()
+            .expect("MemoryStorage locks mutex poisoned");
+
+        if locks.get(terraform_id).map(|row| row.id == lock_id).unwrap_or(false) {
+            locks.remove(terraform_id);
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    async fn force_unlock(&self, terraform_id: &str) -> Result<Option<TerraformLockRow>, StorageError> {
+        let mut locks = self.locks
+            .lock()
+            .expect("MemoryStorage locks mutex poisoned");
+
+        Ok(locks.remove(terraform_id))
+    }
+
+    async fn list_locks(&self) -> Result<Vec<TerraformLockRow>, StorageError> {
+        let locks = self.locks
+            .lock()
+            .expect("MemoryStorage locks mutex poisoned");
+
+        Ok(locks.values().cloned().collect())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio;
+
+    use super::MemoryStorage;
+    use crate::{
+        db::terraform::MaybeConflictError,
+        history::HistoryMode,
+        storage::Storage,
+    };
+
+    #[tokio::test]
+    async fn test_create_replace() {
+        let storage = MemoryStorage::new()
+            .with_history(HistoryMode::Full, 0);
+        let id = "105";
+
+        storage.create_or_replace(id, "initial-state")
+            .await
+            .expect("Failed to create initial_state");
+        storage.create_or_replace(id, "secondary-state")
+            .await
+            .expect("Failed to create secondary_state");
+
+        let row = storage.get(id)
+            .await
+            .expect("Failed to get secondary_state")
+            .expect("No row for secondary_state");
+
+        assert_eq!(row.id, id);
+        assert_eq!(row.state, "secondary-state");
+
+        let versions = storage.list_versions(id)
+            .await
+            .unwrap();
+
+        assert_eq!(versions.len(), 2);
+        assert_eq!(storage.list().await.unwrap().len(), 1);
+        assert_eq!(storage.get_version(id, 1).await.unwrap().unwrap().state, "initial-state");
+        assert!(storage.get_version(id, 0).await.unwrap().is_none());
+        assert!(storage.get_version(id, 3).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let id = "105";
+
+        for (history, retention, expected) in [
+            (HistoryMode::None, 2, vec![]),
+            (HistoryMode::Full, 2, vec![4, 5]),
+            (HistoryMode::Delta, 0, vec![1, 2, 3, 4, 5]),
+        ] {
+            let storage = MemoryStorage::new()
+                .with_history(history, retention);
+
+            for serial in 1..=5 {
+                storage.create_or_replace(id, &serial.to_string())
+                    .await
+                    .unwrap();
+            }
+
+            let versions: Vec<_> = storage
+                .list_versions(id)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|version| version.version)
+                .collect();
+
+            assert_eq!(versions, expected);
+            assert_eq!(storage.get(id).await.unwrap().unwrap().state, "5");
+            assert_eq!(storage.states.lock().unwrap()[id].len(), expected.len().max(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_unlock() {
+        let storage = MemoryStorage::new();
+        let id = "105";
+        let lock_id = "lock_id";
+
+        storage.lock(id, lock_id, "state")
+            .await
+            .expect("Failed to lock resource");
+
+        let conflict = storage.lock(id, "different_lock_id", "state")
+            .await;
+
+        assert!(matches!(conflict, Err(MaybeConflictError::Conflict(ref row)) if row.id == lock_id));
+
+        // unlocking with the wrong lock id leaves the lock in place
+        assert!(!storage.unlock(id, "different_lock_id").await.unwrap());
+        assert!(storage.get_lock_by_terraform_id(id).await.unwrap().is_some());
+        assert_eq!(storage.list_locks().await.unwrap().len(), 1);
+
+        assert!(storage.unlock(id, lock_id).await.unwrap());
+        assert!(storage.get_lock_by_terraform_id(id).await.unwrap().is_none());
+        assert!(storage.list_locks().await.unwrap().is_empty());
+
+        storage.lock(id, lock_id, "state")
+            .await
+            .unwrap();
+
+        let removed = storage.force_unlock(id)
+            .await
+            .unwrap();
+
+        assert_eq!(removed.map(|row| row.id).as_deref(), Some(lock_id));
+        assert!(storage.get_lock_by_terraform_id(id).await.unwrap().is_none());
+    }
+}
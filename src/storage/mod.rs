@@ -0,0 +1,201 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
+
+use axum::async_trait;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::{
+    Error as SqlxError,
+    SqlitePool,
+};
+
+use crate::{
+    config::Configuration,
+    crypto::{
+        CryptoError,
+        Keyring,
+    },
+    db::terraform::{
+        MaybeConflictError,
+        TerraformLockRow,
+        TerraformQuery,
+        TerraformRow,
+    },
+};
+
+pub use memory::MemoryStorage;
+pub use s3::{
+    S3Client,
+    S3Storage,
+};
+
+pub mod memory;
+pub mod s3;
+pub mod sqlite;
+
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+
+/// `DATABASE_URI` which keeps all state and locks in process memory
+pub const MEMORY_URI: &str = "memory://";
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Sqlite,
+    S3,
+}
+
+
+/// A stored state without its body
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct StateSummary {
+    pub id: String,
+    pub last_update_ts: NaiveDateTime,
+}
+
+
+/// A previously written version of a state
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct StateVersion {
+    pub version: i64,
+    pub last_update_ts: NaiveDateTime,
+}
+
+
+#[derive(Debug)]
+pub enum StorageError {
+    Codec(String),
+    Database(SqlxError),
+    Encryption(String),
+    ObjectStore(String),
+}
+
+
+/// Persistence for terraform state documents and their locks
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Returns an optional terraform row for a given terraform id
+    async fn get(&self, id: &str) -> Result<Option<TerraformRow>, StorageError>;
+
+    async fn create_or_replace(&self, id: &str, state: &str) -> Result<TerraformRow, StorageError>;
+
+    /// Lists every stored state ordered by id
+    async fn list(&self) -> Result<Vec<StateSummary>, StorageError>;
+
+    /// Lists the retained versions of a state, oldest first
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersion>, StorageError>;
+
+    async fn get_version(&self, id: &str, version: i64) -> Result<Option<TerraformRow>, StorageError>;
+
+    async fn get_lock_by_terraform_id(&self, terraform_id: &str) -> Result<Option<TerraformLockRow>, StorageError>;
+
+    /// Attempts to obtain the lock on a terraform resource, returning a
+    /// Conflict if the resource is locked by another lock id
+    async fn lock(&self, terraform_id: &str, lock_id: &str, state: &str) -> Result<TerraformLockRow, MaybeConflictError>;
+
+    /// Removes the lock on a resource if it is held by `lock_id`, returning
+    /// whether it was
+    async fn unlock(&self, terraform_id: &str, lock_id: &str) -> Result<bool, StorageError>;
+
+    /// Removes the lock on a resource whoever holds it, returning the lock
+    /// that was removed
+    async fn force_unlock(&self, terraform_id: &str) -> Result<Option<TerraformLockRow>, StorageError>;
+
+    /// Returns every lock currently held
+    async fn list_locks(&self) -> Result<Vec<TerraformLockRow>, StorageError>;
+
+    /// The database pool backing this storage, if any
+    fn pool(&self) -> Option<&SqlitePool> {
+        None
+    }
+}
+
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sqlite" => Ok(Self::Sqlite),
+            "s3" => Ok(Self::S3),
+            other => Err(format!("Unknown storage backend: {}", other)),
+        }
+    }
+}
+
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Codec(e) => write!(f, "Codec error: {}", e),
+            Self::Database(e) => write!(f, "Database error: {}", e),
+            Self::Encryption(e) => write!(f, "Encryption error: {}", e),
+            Self::ObjectStore(e) => write!(f, "Object store error: {}", e),
+        }
+    }
+}
+
+
+impl std::error::Error for StorageError {}
+
+
+impl From<SqlxError> for StorageError {
+    fn from(e: SqlxError) -> Self {
+        Self::Database(e)
+    }
+}
+
+
+impl From<CryptoError> for StorageError {
+    fn from(e: CryptoError) -> Self {
+        Self::Encryption(e.0)
+    }
+}
+
+
+/// Checks the storage settings without connecting to the database or the
+/// object store
+pub fn check_config(config: &Configuration) -> Result<(), StorageError> {
+    let keyring = Keyring::from_config(config)?;
+
+    match config.storage_backend {
+        StorageBackend::Sqlite => (),
+        StorageBackend::S3 if keyring.is_some() => return Err(s3_encryption_unsupported()),
+        StorageBackend::S3 => {
+            S3Client::from_config(config)?;
+        },
+    }
+
+    Ok(())
+}
+
+
+/// Builds the configured storage backend; the pool always holds locks
+pub async fn from_config(config: &Configuration, pool: SqlitePool) -> Result<SharedStorage, StorageError> {
+    let keyring = Keyring::from_config(config)?
+        .map(Arc::new);
+
+    let storage: SharedStorage = match config.storage_backend {
+        StorageBackend::Sqlite => Arc::new(
+            TerraformQuery::new(pool)
+                .with_keyring(keyring)
+                .with_codec(config.state_compression)
+                .with_history(config.state_history, config.state_history_snapshot_interval)
+                .with_retention(config.state_history_retention),
+        ),
+        StorageBackend::S3 if keyring.is_some() => return Err(s3_encryption_unsupported()),
+        StorageBackend::S3 => Arc::new(S3Storage::from_config(config, pool)?),
+    };
+
+    Ok(storage)
+}
+
+
+fn s3_encryption_unsupported() -> StorageError {
+    StorageError::Encryption("Encryption at rest is only supported by the sqlite storage backend".to_string())
+}
@@ -0,0 +1,63 @@
+use axum::async_trait;
+use sqlx::SqlitePool;
+
+use super::{
+    StateSummary,
+    StateVersion,
+    Storage,
+    StorageError,
+};
+use crate::db::terraform::{
+    MaybeConflictError,
+    TerraformLockRow,
+    TerraformQuery,
+    TerraformRow,
+};
+
+
+#[async_trait]
+impl Storage for TerraformQuery {
+    async fn get(&self, id: &str) -> Result<Option<TerraformRow>, StorageError> {
+        TerraformQuery::get(self, id).await
+    }
+
+    async fn create_or_replace(&self, id: &str, state: &str) -> Result<TerraformRow, StorageError> {
+        TerraformQuery::create_or_replace(self, id, state).await
+    }
+
+    async fn list(&self) -> Result<Vec<StateSummary>, StorageError> {
+        Ok(TerraformQuery::list(self).await?)
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersion>, StorageError> {
+        TerraformQuery::list_versions(self, id).await
+    }
+
+    async fn get_version(&self, id: &str, version: i64) -> Result<Option<TerraformRow>, StorageError> {
+        TerraformQuery::get_version(self, id, version).await
+    }
+
+    async fn get_lock_by_terraform_id(&self, terraform_id: &str) -> Result<Option<TerraformLockRow>, StorageError> {
+        Ok(TerraformQuery::get_lock_by_terraform_id(self, terraform_id).await?)
+    }
+
+    async fn lock(&self, terraform_id: &str, lock_id: &str, state: &str) -> Result<TerraformLockRow, MaybeConflictError> {
+        TerraformQuery::lock(self, terraform_id, lock_id, state).await
+    }
+
+    async fn unlock(&self, terraform_id: &str, lock_id: &str) -> Result<bool, StorageError> {
+        Ok(TerraformQuery::unlock(self, terraform_id, lock_id).await?)
+    }
+
+    async fn force_unlock(&self, terraform_id: &str) -> Result<Option<TerraformLockRow>, StorageError> {
+        Ok(TerraformQuery::force_unlock(self, terraform_id).await?)
+    }
+
+    async fn list_locks(&self) -> Result<Vec<TerraformLockRow>, StorageError> {
+        Ok(TerraformQuery::list_locks(self).await?)
+    }
+
+    fn pool(&self) -> Option<&SqlitePool> {
+        Some(TerraformQuery::pool(self))
+    }
+}
@@ -0,0 +1,609 @@
+use std::{
+    collections::{
+        BTreeMap,
+        HashSet,
+    },
+    path::Path,
+    time::Duration,
+};
+
+use chrono::{
+    NaiveDateTime,
+    Utc,
+};
+use futures::future;
+use hmac::{
+    Hmac,
+    Mac,
+};
+use hyper::{
+    Body,
+    Client,
+    Method,
+    Request,
+    Uri,
+    client::HttpConnector,
+};
+use hyper_tls::HttpsConnector;
+use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::{
+    Error as SqlxError,
+    SqlitePool,
+};
+
+use crate::{
+    auth::glob_match,
+    config::{
+        self,
+        ConfigError,
+        Configuration,
+    },
+    db::outbox::{
+        OutboxQuery,
+        OutboxRow,
+    },
+    events::{
+        Event,
+        EventKind,
+        SharedEvents,
+    },
+    storage::SharedStorage,
+};
+
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub type WebhookClient = Client<HttpsConnector<HttpConnector>>;
+
+
+/// How long the dispatcher sleeps when no event wakes it
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often held locks are checked for staleness
+const STALE_LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often deliveries that were given up on are purged from the outbox
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long deliveries that were given up on are kept for inspection
+const GIVEN_UP_RETENTION_DAYS: i64 = 7;
+
+/// Deliveries attempted per pass of the dispatcher
+const BATCH_SIZE: i64 = 100;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Retries wait this long, doubling with each failure up to `MAX_BACKOFF`
+const BASE_BACKOFF_SECONDS: i64 = 10;
+const MAX_BACKOFF_SECONDS: i64 = 3600;
+
+
+/// An endpoint to notify of events, configured in `WEBHOOKS_FILE`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub name: String,
+    pub url: String,
+    /// Key for the HMAC-SHA256 signature sent with every payload
+    secret: String,
+    /// Events to send; every event if empty
+    #[serde(default)]
+    events: Vec<EventKind>,
+    /// Workspace id patterns where `*` matches any run of characters
+    #[serde(default = "all_workspaces")]
+    workspaces: Vec<String>,
+}
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhooksFile {
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
+}
+
+
+impl Webhook {
+    pub fn from_config(config: &Configuration) -> Result<Vec<Self>, ConfigError> {
+        let file = match &config.webhooks_file {
+            Some(path) => config::parse_file::<WebhooksFile>(Path::new(path))?,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut names = HashSet::new();
+
+        for hook in file.webhooks.iter() {
+            if !names.insert(&hook.name) {
+                return Err(ConfigError::Invalid(format!("Webhook {} is defined more than once", hook.name)));
+            }
+
+            let scheme = hook.url
+                .parse::<Uri>()
+                .ok()
+                .and_then(|uri| uri.scheme_str().map(|scheme| scheme.to_string()));
+
+            if !matches!(scheme.as_deref(), Some("http") | Some("https")) {
+                return Err(ConfigError::Invalid(format!("Webhook {} url {} is not an http or https URL", hook.name, hook.url)));
+            }
+
+            if hook.secret.is_empty() {
+                return Err(ConfigError::Invalid(format!("Webhook {} has an empty secret", hook.name)));
+            }
+        }
+
+        Ok(file.webhooks)
+    }
+
+    pub fn accepts(&self, event: &Event) -> bool {
+        (self.events.is_empty() || self.events.contains(&event.event))
+            && self.workspaces.iter().any(|pattern| glob_match(pattern, &event.workspace))
+    }
+}
+
+
+/// Adds a delivery of `event` to the outbox for each hook accepting it,
+/// returning how many were added
+pub async fn enqueue(pool: &SqlitePool, hooks: &[Webhook], event: &Event) -> Result<usize, SqlxError> {
+    let payload = serde_json::to_string(event)
+        .expect("Events always serialize");
+    let query = OutboxQuery::new(pool.clone());
+    let mut queued = 0;
+
+    for hook in hooks.iter().filter(|hook| hook.accepts(event)) {
+        query.enqueue(hook.name.as_str(), event.event.as_str(), payload.as_str(), event.timestamp)
+            .await?;
+
+        queued += 1;
+    }
+
+    Ok(queued)
+}
+
+
+/// Sends outbox deliveries as they become due, until the process exits
+pub async fn deliver(pool: SqlitePool, events: SharedEvents) {
+    let client: WebhookClient = Client::builder().build(HttpsConnector::new());
+
+    loop {
+        let settings = events
+            .config()
+            .current();
+        let attempted = deliver_due(&pool, &client, &settings.webhooks, settings.config.webhook_max_attempts, Utc::now().naive_utc())
+            .await;
+
+        match attempted {
+            // more may be due already
+            Ok(attempted) if attempted as i64 == BATCH_SIZE => continue,
+            Ok(_) => (),
+            Err(e) => tracing::error!("Failed to read the webhook outbox: {}", e),
+        }
+
+        tokio::select! {
+            _ = events.published() => (),
+            _ = tokio::time::sleep(POLL_INTERVAL) => (),
+        }
+    }
+}
+
+
+/// Removes deliveries given up on more than `GIVEN_UP_RETENTION_DAYS` after
+/// they were queued, until the process exits
+pub async fn purge_given_up(pool: SqlitePool) {
+    let query = OutboxQuery::new(pool);
+    let mut ticks = tokio::time::interval(PURGE_INTERVAL);
+
+    loop {
+        ticks.tick().await;
+
+        let before = Utc::now().naive_utc() - chrono::Duration::days(GIVEN_UP_RETENTION_DAYS);
+
+        match query.purge_given_up(before).await {
+            Ok(0) => (),
+            Ok(purged) => tracing::info!("Purged {} webhook deliveries that were given up on", purged),
+            Err(e) => tracing::error!("Failed to purge the webhook outbox: {}", e),
+        }
+    }
+}
+
+
+/// Handles every delivery due by `now`, returning how many there were.
+/// Delivered rows are removed; failed ones are retried with exponential
+/// backoff until `max_attempts` is reached. Hooks are sent to concurrently so
+/// a slow endpoint does not hold up the others.
+pub async fn deliver_due(
+    pool: &SqlitePool,
+    client: &WebhookClient,
+    hooks: &[Webhook],
+    max_attempts: i64,
+    now: NaiveDateTime,
+) -> Result<usize, SqlxError> {
+    let query = OutboxQuery::new(pool.clone());
+    let due = query
+        .due(now, BATCH_SIZE)
+        .await?;
+
+    let mut by_hook: BTreeMap<&str, Vec<&OutboxRow>> = BTreeMap::new();
+
+    for row in due.iter() {
+        by_hook
+            .entry(&row.hook)
+            .or_default()
+            .push(row);
+    }
+
+    let deliveries = by_hook
+        .into_iter()
+        .map(|(name, rows)| deliver_to_hook(&query, client, hooks.iter().find(|hook| hook.name == name), rows, max_attempts, now));
+
+    future::try_join_all(deliveries)
+        .await?;
+
+    Ok(due.len())
+}
+
+
+/// Sends one hook's deliveries in order. After a failure the rest are
+/// postponed instead of attempted, so an unreachable endpoint costs at most
+/// one timeout per pass.
+async fn deliver_to_hook(
+    query: &OutboxQuery,
+    client: &WebhookClient,
+    hook: Option<&Webhook>,
+    rows: Vec<&OutboxRow>,
+    max_attempts: i64,
+    now: NaiveDateTime,
+) -> Result<(), SqlxError> {
+    let hook = match hook {
+        Some(hook) => hook,
+        None => {
+            for row in rows {
+                query.fail(row.id, "webhook is no longer configured", None)
+                    .await?;
+            }
+
+            return Ok(());
+        },
+    };
+
+    let mut rows = rows.into_iter();
+
+    for row in rows.by_ref() {
+        match send(client, hook, row).await {
+            Ok(()) => {
+                query.delete(row.id)
+                    .await?;
+            },
+            Err(e) => {
+                let attempts = row.attempts + 1;
+                let retry = if attempts < max_attempts {
+                    Some(now + backoff(attempts))
+                } else {
+                    tracing::error!("Giving up on {} delivery {} to {} after {} attempts: {}", row.event, row.id, hook.name, attempts, e);
+                    None
+                };
+
+                if retry.is_some() {
+                    tracing::warn!("Failed {} delivery {} to {}, attempt {}: {}", row.event, row.id, hook.name, attempts, e);
+                }
+
+                query.fail(row.id, &e, retry)
+                    .await?;
+
+                break;
+            },
+        }
+    }
+
+    for row in rows {
+        query.postpone(row.id, now + backoff(1))
+            .await?;
+    }
+
+    Ok(())
+}
+
+
+/// Publishes `lock.stale` once for each lock held longer than `stale_after`
+pub async fn watch_locks(storage: SharedStorage, events: SharedEvents, stale_after: Duration) {
+    let stale_after = chrono::Duration::from_std(stale_after)
+        .unwrap_or_else(|_| chrono::Duration::max_value());
+    let mut ticks = tokio::time::interval(STALE_LOCK_CHECK_INTERVAL);
+    let mut reported = HashSet::new();
+
+    loop {
+        ticks.tick().await;
+
+        let locks = match storage.list_locks().await {
+            Ok(locks) => locks,
+            Err(e) => {
+                tracing::error!("Failed to list locks: {}", e);
+                continue;
+            },
+        };
+
+        let now = Utc::now().naive_utc();
+        let stale: HashSet<_> = locks
+            .iter()
+            .filter(|lock| now - lock.last_update_ts >= stale_after)
+            .map(|lock| (lock.terraform_id.clone(), lock.id.clone()))
+            .collect();
+
+        for lock in locks.iter() {
+            let key = (lock.terraform_id.clone(), lock.id.clone());
+
+            if stale.contains(&key) && !reported.contains(&key) {
+                events.publish(Event::new(EventKind::LockStale, &lock.terraform_id).with_lock(&lock.state))
+                    .await;
+            }
+        }
+
+        // forget locks that have been released so a new stale lock is reported
+        reported = stale;
+    }
+}
+
+
+/// Value of the `X-Webhook-Signature` header for `payload`
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+
+    mac.update(payload.as_bytes());
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+
+async fn send(client: &WebhookClient, hook: &Webhook, row: &OutboxRow) -> Result<(), String> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&hook.url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", concat!("terraform-http-backend-rs/", env!("CARGO_PKG_VERSION")))
+        .header("X-Webhook-Event", &row.event)
+        .header("X-Webhook-Delivery", row.id)
+        .header("X-Webhook-Signature", sign(&hook.secret, &row.payload))
+        .body(Body::from(row.payload.clone()))
+        .map_err(|e| e.to_string())?;
+
+    let response = tokio::time::timeout(DELIVERY_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| format!("no response within {}s", DELIVERY_TIMEOUT.as_secs()))?
+        .map_err(|e| e.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("responded {}", response.status()))
+    }
+}
+
+
+fn backoff(attempts: i64) -> chrono::Duration {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+
+    chrono::Duration::seconds((BASE_BACKOFF_SECONDS * 2i64.pow(exponent)).min(MAX_BACKOFF_SECONDS))
+}
+
+
+fn all_workspaces() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::{
+            SocketAddr,
+            TcpListener,
+        },
+        str::FromStr,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    };
+
+    use chrono::Utc;
+    use hyper::{
+        Body,
+        Client,
+        Request,
+        Response,
+        Server,
+        service::{
+            make_service_fn,
+            service_fn,
+        },
+    };
+    use hyper_tls::HttpsConnector;
+    use sqlx::{
+        SqlitePool,
+        sqlite::SqliteConnectOptions,
+    };
+    use tokio;
+
+    use super::{
+        Webhook,
+        backoff,
+        deliver_due,
+        enqueue,
+        sign,
+    };
+    use crate::{
+        database,
+        db::outbox::OutboxQuery,
+        events::{
+            Event,
+            EventKind,
+        },
+    };
+
+    type Received = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    /// Records requests, failing every other one starting with the first
+    fn receiver() -> (SocketAddr, Received) {
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let recorder = received.clone();
+
+        let make_service = make_service_fn(move |_| {
+            let recorder = recorder.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let recorder = recorder.clone();
+
+                    async move {
+                        let header = |name: &str| {
+                            request.headers()
+                                .get(name)
+                                .and_then(|value| value.to_str().ok())
+                                .unwrap_or_default()
+                                .to_string()
+                        };
+                        let event = header("X-Webhook-Event");
+                        let signature = header("X-Webhook-Signature");
+                        let body = hyper::body::to_bytes(request.into_body())
+                            .await
+                            .unwrap();
+                        let mut received = recorder
+                            .lock()
+                            .unwrap();
+
+                        received.push((event, signature, String::from_utf8(body.to_vec()).unwrap()));
+
+                        let status = if received.len() % 2 == 1 { 500 } else { 200 };
+
+                        Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(make_service);
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        (addr, received)
+    }
+
+    fn hook(url: String, events: &str, workspaces: &str) -> Webhook {
+        toml::from_str(&format!("name = \"test\"\nurl = \"{}\"\nsecret = \"s3cret\"\nevents = {}\nworkspaces = {}\n", url, events, workspaces))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_filters() {
+        let all = hook("http://localhost/".to_string(), "[]", "[\"*\"]");
+        let prod_writes = hook("http://localhost/".to_string(), "[\"state.written\"]", "[\"prod-*\"]");
+
+        assert!(all.accepts(&Event::new(EventKind::LockStale, "anything")));
+        assert!(prod_writes.accepts(&Event::new(EventKind::StateWritten, "prod-network")));
+        assert!(!prod_writes.accepts(&Event::new(EventKind::LockAcquired, "prod-network")));
+        assert!(!prod_writes.accepts(&Event::new(EventKind::StateWritten, "staging-network")));
+
+        assert_eq!(backoff(1).num_seconds(), 10);
+        assert_eq!(backoff(3).num_seconds(), 40);
+        assert_eq!(backoff(50).num_seconds(), 3600);
+    }
+
+    #[tokio::test]
+    async fn test_delivery() {
+        let opts = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap();
+        let pool = SqlitePool::connect_with(opts)
+            .await
+            .unwrap();
+
+        database::MIGRATE.run(&pool)
+            .await
+            .unwrap();
+
+        let (addr, received) = receiver();
+        let hooks = vec![hook(format!("http://{}/hook", addr), "[\"state.written\"]", "[\"*\"]")];
+        let client = Client::builder().build(HttpsConnector::new());
+
+        let queued = enqueue(&pool, &hooks, &Event::new(EventKind::StateWritten, "105").with_actor("asdf"))
+            .await
+            .unwrap();
+        let filtered = enqueue(&pool, &hooks, &Event::new(EventKind::LockAcquired, "105"))
+            .await
+            .unwrap();
+
+        assert_eq!((queued, filtered), (1, 0));
+
+        // the first attempt fails and is retried after the backoff
+        let now = Utc::now().naive_utc();
+
+        assert_eq!(deliver_due(&pool, &client, &hooks, 10, now).await.unwrap(), 1);
+        assert_eq!(deliver_due(&pool, &client, &hooks, 10, now).await.unwrap(), 0);
+        assert_eq!(deliver_due(&pool, &client, &hooks, 10, now + backoff(1)).await.unwrap(), 1);
+        assert_eq!(deliver_due(&pool, &client, &hooks, 10, now + backoff(10)).await.unwrap(), 0);
+
+        let received = received
+            .lock()
+            .unwrap()
+            .clone();
+
+        assert_eq!(received.len(), 2);
+
+        let (event, signature, body) = &received[1];
+
+        assert_eq!(event, "state.written");
+        assert_eq!(signature, &sign("s3cret", body));
+        assert!(body.contains("\"workspace\":\"105\""));
+        assert!(body.contains("\"actor\":\"asdf\""));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_hook() {
+        let opts = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap();
+        let pool = SqlitePool::connect_with(opts)
+            .await
+            .unwrap();
+
+        database::MIGRATE.run(&pool)
+            .await
+            .unwrap();
+
+        // nothing listens on a port once its listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let hooks = vec![hook(format!("http://{}/hook", addr), "[]", "[\"*\"]")];
+        let client = Client::builder().build(HttpsConnector::new());
+
+        for workspace in ["105", "106", "107"] {
+            enqueue(&pool, &hooks, &Event::new(EventKind::StateWritten, workspace))
+                .await
+                .unwrap();
+        }
+
+        let now = Utc::now().naive_utc();
+
+        assert_eq!(deliver_due(&pool, &client, &hooks, 10, now).await.unwrap(), 3);
+        assert_eq!(deliver_due(&pool, &client, &hooks, 10, now).await.unwrap(), 0);
+
+        // only the first delivery was attempted; the rest wait for it
+        let attempts: Vec<(i64,)> = sqlx::query_as("SELECT attempts FROM webhook_outbox ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(attempts, vec![(1,), (0,), (0,)]);
+
+        // given up deliveries are purged once they are old enough
+        let outbox = OutboxQuery::new(pool.clone());
+
+        assert_eq!(deliver_due(&pool, &client, &hooks, 1, now + backoff(1)).await.unwrap(), 3);
+        assert_eq!(outbox.purge_given_up(now - chrono::Duration::days(1)).await.unwrap(), 0);
+        assert_eq!(outbox.purge_given_up(now + chrono::Duration::days(1)).await.unwrap(), 1);
+    }
+}